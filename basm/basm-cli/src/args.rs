@@ -0,0 +1,154 @@
+/// Sets the one input a tool takes, erroring if it's already been given
+pub fn set_input<T>(input: &mut Option<T>, new_input: T) -> Result<(), String> {
+    if input.is_some() {
+        return Err("Too many inputs provided, only one file can be used at a time".to_owned());
+    }
+    *input = Some(new_input);
+    Ok(())
+}
+
+/// Gets the value of a long option, either from `--flag=value` or from the next argument
+pub fn option_value<I>(
+    flag: &str,
+    inline_value: Option<String>,
+    args: &mut I,
+) -> Result<String, String>
+where
+    I: Iterator<Item = String>,
+{
+    match inline_value {
+        Some(v) => Ok(v),
+        None => args
+            .next()
+            .ok_or_else(|| format!("Option {} expects a value", flag)),
+    }
+}
+
+/// Gets the value of a short option, either attached (`-Ifoo`) or from the next argument (`-I foo`)
+pub fn short_value<I>(short: &str, arg: &str, args: &mut I) -> Result<String, String>
+where
+    I: Iterator<Item = String>,
+{
+    match &arg[short.len()..] {
+        "" => args
+            .next()
+            .ok_or_else(|| format!("Option {} expects a value", short)),
+        attached => Ok(attached.to_owned()),
+    }
+}
+
+/// Splits `NAME=VALUE` (or just `NAME`) into a define's name and its replacement text
+pub fn parse_define(def: &str) -> Result<(String, String), String> {
+    let (name, value) = def.split_once('=').unwrap_or((def, "1"));
+    if !is_name(name) {
+        return Err(format!("-D expects a name to define, found '{}'", name));
+    }
+
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+/// Checks the name given to -U
+pub fn parse_undef(name: &str) -> Result<String, String> {
+    match is_name(name) {
+        true => Ok(name.to_owned()),
+        false => Err(format!("-U expects a name to undefine, found '{}'", name)),
+    }
+}
+
+/// Whether name can be defined: a letter or `_`, followed by letters, digits and `_`
+pub fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c == '_' || c.is_alphabetic())
+        && name.chars().all(|c| c == '_' || c.is_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn short_values_can_be_attached_or_separate() {
+        assert_eq!(
+            short_value("-o", "-ofoo", &mut args(&[])),
+            Ok("foo".to_owned())
+        );
+        assert_eq!(
+            short_value("-I", "-Ia", &mut args(&["b"])),
+            Ok("a".to_owned())
+        );
+
+        let mut rest = args(&["foo", "bar"]);
+        assert_eq!(short_value("-o", "-o", &mut rest), Ok("foo".to_owned()));
+        assert_eq!(rest.next().as_deref(), Some("bar"));
+    }
+
+    #[test]
+    fn long_values_can_be_inline_or_separate() {
+        let inline = Some("never".to_owned());
+        assert_eq!(
+            option_value("--color", inline, &mut args(&["always"])),
+            Ok("never".to_owned())
+        );
+        assert_eq!(
+            option_value("--color", None, &mut args(&["always"])),
+            Ok("always".to_owned())
+        );
+        assert_eq!(
+            option_value("--color", Some(String::new()), &mut args(&[])),
+            Ok(String::new())
+        );
+    }
+
+    #[test]
+    fn missing_values_are_errors() {
+        let error = Err("Option --map expects a value".to_owned());
+        assert_eq!(option_value("--map", None, &mut args(&[])), error);
+        let error = Err("Option -o expects a value".to_owned());
+        assert_eq!(short_value("-o", "-o", &mut args(&[])), error);
+    }
+
+    // Options are read before `--` is looked for, so it can still be an option's value
+    #[test]
+    fn values_are_taken_as_written() {
+        assert_eq!(
+            short_value("-o", "-o", &mut args(&["--"])),
+            Ok("--".to_owned())
+        );
+        assert_eq!(
+            option_value("--map", None, &mut args(&["-"])),
+            Ok("-".to_owned())
+        );
+    }
+
+    #[test]
+    fn only_one_input_can_be_set() {
+        let mut input = None;
+        assert_eq!(set_input(&mut input, "-"), Ok(()));
+        assert!(set_input(&mut input, "main.basm").is_err());
+        assert_eq!(input, Some("-"));
+    }
+
+    #[test]
+    fn names_start_with_a_letter_or_underscore() {
+        assert!(is_name("x") && is_name("_") && is_name("_x1"));
+        assert!(!is_name("") && !is_name("1") && !is_name("a-b"));
+    }
+
+    #[test]
+    fn defines_default_to_1() {
+        assert_eq!(parse_define("X"), Ok(("X".to_owned(), "1".to_owned())));
+        assert_eq!(
+            parse_define("X=a=b"),
+            Ok(("X".to_owned(), "a=b".to_owned()))
+        );
+        assert_eq!(parse_define("X="), Ok(("X".to_owned(), String::new())));
+        assert!(parse_define("=1").is_err());
+        assert!(parse_undef("1X").is_err());
+    }
+}
@@ -0,0 +1,5 @@
+//! Command line and terminal helpers shared by basm, basm-preprocessor and w4096, so they read
+//! their arguments and print diagnostics the same way.
+
+pub mod args;
+pub mod term;
@@ -0,0 +1,75 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+pub const RED: &str = "\x1b[91m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const MAGENTA: &str = "\x1b[95m";
+pub const GRAY: &str = "\x1b[90m";
+const RESET: &str = "\x1b[0m";
+
+static COLOR: AtomicBool = AtomicBool::new(true);
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,   // Errors only
+    Normal,  // Errors and warnings
+    Verbose, // Everything, including progress information
+}
+
+/// Decides once whether diagnostics get ANSI colors. Auto colors only when stderr is a terminal
+/// and NO_COLOR isn't set.
+pub fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+        }
+    };
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity_at_least(verbosity: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= verbosity as u8
+}
+
+/// Wraps text in the given color code, or returns it unchanged if colors are disabled
+pub fn paint(color: &str, text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Formats the header used for errors on a line, naming the file if it's known
+pub fn error_at(filename: Option<&str>, line: usize) -> String {
+    match filename {
+        Some(filename) => paint(RED, &format!("Error on line {} of {}:", line, filename)),
+        None => paint(RED, &format!("Error on line {}:", line)),
+    }
+}
+
+/// Prints an error after prefix, which names the tool it came from
+pub fn error(prefix: &str, msg: &str) {
+    eprintln!("{} {}", paint(MAGENTA, prefix), msg);
+}
+
+/// Prints progress information after prefix, but only when verbose
+pub fn info(prefix: &str, msg: &str) {
+    if verbosity_at_least(Verbosity::Verbose) {
+        eprintln!("{} {}", paint(MAGENTA, prefix), paint(GRAY, msg));
+    }
+}
@@ -0,0 +1,410 @@
+use serde::{Deserialize, Serialize};
+
+/// The map format written by this version of the toolchain. Maps without a version were written
+/// before it was added, and are read as version 0. Version 1 also gave each line a column, which
+/// was always 1 and is ignored now.
+pub const VERSION: u32 = 2;
+
+/// Says where each line of preprocessed output came from, so the assembler can report errors at
+/// the original file and line
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CodeMap {
+    #[serde(default)]
+    pub version: u32,
+    pub filenames: Vec<String>,
+    pub line_entries: Vec<LineEntry>, // One for each line of output, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expansions: Vec<Expansion>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub filename_index: usize,
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expansion: Option<usize>, // Index into expansions, if the line came from a macro
+}
+
+/// A macro use that produced some of the output. Lines written by the macro point at it, and it
+/// points at whichever expansion it was itself written by.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: String,
+    pub filename_index: usize,
+    pub line: usize,
+    pub column: usize, // Where the macro's name starts on the line, counting from 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<usize>,
+}
+
+/// Where a line of output or a macro use came from. Only macro uses have a column, since a line
+/// is the whole of its source line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub filename: &'a str,
+    pub line: usize,
+    pub column: Option<usize>,
+}
+
+impl CodeMap {
+    pub fn new() -> Self {
+        Self {
+            version: VERSION,
+            ..Self::default()
+        }
+    }
+
+    pub fn add_entry(&mut self, filename_index: usize, line: usize) {
+        self.line_entries.push(LineEntry {
+            filename_index,
+            line,
+            expansion: None,
+        });
+    }
+
+    /// Records that the macro name was used at the given place, returning the index to give the
+    /// entries of the lines it writes
+    pub fn add_expansion(
+        &mut self,
+        name: &str,
+        filename_index: usize,
+        line: usize,
+        column: usize,
+        parent: Option<usize>,
+    ) -> usize {
+        self.expansions.push(Expansion {
+            name: name.to_owned(),
+            filename_index,
+            line,
+            column,
+            parent,
+        });
+        self.expansions.len() - 1
+    }
+
+    /// Returns the index of filename in self.filenames, adding it if it isn't there yet
+    pub fn filename_index(&mut self, filename: &str) -> usize {
+        match self.filenames.iter().position(|f| f == filename) {
+            Some(i) => i,
+            None => {
+                self.filenames.push(filename.to_owned());
+                self.filenames.len() - 1
+            }
+        }
+    }
+
+    /// Appends the entries of another map, as if its output was written after this one's
+    pub fn push(&mut self, other: &Self) {
+        // Where each of the other map's files ends up in this one's
+        let filename_indices: Vec<usize> = other
+            .filenames
+            .iter()
+            .map(|f| self.filename_index(f))
+            .collect();
+        let expansion_offset = self.expansions.len(); // How much to add to each expansion index
+
+        for entry in &other.line_entries {
+            self.line_entries.push(LineEntry {
+                filename_index: filename_indices[entry.filename_index],
+                expansion: entry.expansion.map(|e| e + expansion_offset),
+                ..*entry
+            });
+        }
+        for expansion in &other.expansions {
+            self.expansions.push(Expansion {
+                filename_index: filename_indices[expansion.filename_index],
+                parent: expansion.parent.map(|e| e + expansion_offset),
+                ..expansion.clone()
+            });
+        }
+    }
+
+    /// Returns the entry for a line of output, counting from 1
+    pub fn entry(&self, line: usize) -> Option<&LineEntry> {
+        self.line_entries.get(line.checked_sub(1)?)
+    }
+
+    /// Returns where a line of output came from, counting from 1
+    pub fn location(&self, line: usize) -> Option<Location<'_>> {
+        let entry = self.entry(line)?;
+        Some(Location {
+            filename: self.filenames.get(entry.filename_index)?,
+            line: entry.line,
+            column: None,
+        })
+    }
+
+    pub fn get_from(&self, line: usize) -> (String, usize) {
+        match self.location(line) {
+            Some(location) => (location.filename.to_owned(), location.line),
+            None => ("<unknown>".to_owned(), line),
+        }
+    }
+
+    /// Returns the macro uses that led to a line of output, innermost first
+    pub fn expansions_of(&self, line: usize) -> Vec<&Expansion> {
+        let mut chain = Vec::new();
+        let mut next = self.entry(line).and_then(|e| e.expansion);
+        while let Some(expansion) = next.and_then(|i| self.expansions.get(i)) {
+            chain.push(expansion);
+            next = expansion.parent;
+        }
+        chain
+    }
+
+    /// Returns where an expansion was used
+    pub fn expansion_location(&self, expansion: &Expansion) -> Location<'_> {
+        Location {
+            filename: &self.filenames[expansion.filename_index],
+            line: expansion.line,
+            column: Some(expansion.column),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Code maps can always be serialized")
+    }
+
+    /// Reads a map written by to_json, refusing ones from newer versions of the toolchain
+    pub fn from_json(data: &str) -> Result<CodeMap, String> {
+        let map: CodeMap = match serde_json::from_str(data) {
+            Ok(map) => map,
+            Err(e) => return Err(format!("Couldn't read code map. Error: {e}")),
+        };
+
+        if map.version > VERSION {
+            return Err(format!(
+                "The code map is version {}, but only versions up to {} are understood",
+                map.version, VERSION
+            ));
+        }
+
+        let valid = map.line_entries.iter().all(|e| {
+            e.filename_index < map.filenames.len()
+                && e.expansion.is_none_or(|i| i < map.expansions.len())
+        }) && map.expansions.iter().enumerate().all(|(i, e)| {
+            // Parents are always added before the expansions inside them
+            e.filename_index < map.filenames.len() && e.parent.is_none_or(|p| p < i)
+        });
+        if !valid {
+            return Err("The code map refers to files or expansions it doesn't have".to_owned());
+        }
+
+        Ok(map)
+    }
+}
+
+impl CodeMap {
+    /// Writes `#line N "file"` markers into output wherever a line doesn't follow on from the one
+    /// before it, so basm can recover each line's origin without a map file. Returns the marked
+    /// output along with its map, where each marker line maps to the line it introduces.
+    pub fn with_line_markers(&self, output: &str) -> (String, CodeMap) {
+        let mut marked = String::new();
+        let mut map = CodeMap::new();
+        map.filenames = self.filenames.clone();
+        map.expansions = self.expansions.clone();
+
+        let mut previous: Option<&LineEntry> = None;
+        for (i, text) in output.split('\n').enumerate() {
+            if i != 0 {
+                marked.push('\n');
+            }
+
+            let entry = match self.line_entries.get(i) {
+                Some(e) => e,
+                None => {
+                    // No information about this line, so leave it as is
+                    marked.push_str(text);
+                    continue;
+                }
+            };
+
+            let follows_previous = matches!(
+                previous,
+                Some(p) if p.filename_index == entry.filename_index && p.line + 1 == entry.line
+            );
+            if !follows_previous {
+                marked.push_str(&line_marker(
+                    &self.filenames[entry.filename_index],
+                    entry.line,
+                ));
+                marked.push('\n');
+                map.line_entries.push(*entry);
+            }
+
+            marked.push_str(text);
+            map.line_entries.push(*entry);
+            previous = Some(entry);
+        }
+
+        (marked, map)
+    }
+}
+
+/// Formats a marker saying that the next line is the given line of filename
+fn line_marker(filename: &str, line: usize) -> String {
+    let filename = filename.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("#line {} \"{}\"", line, filename)
+}
+
+impl std::fmt::Display for CodeMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Filenames:")?;
+        for name in &self.filenames {
+            writeln!(f, "  {}", name)?;
+        }
+        writeln!(f, "Lines:")?;
+        for entry in &self.line_entries {
+            write!(
+                f,
+                "  {}:{}",
+                self.filenames[entry.filename_index], entry.line
+            )?;
+            if let Some(i) = entry.expansion {
+                write!(f, " (in {})", self.expansions[i].name)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.filename, self.line)?;
+        match self.column {
+            Some(column) => write!(f, ":{}", column),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> CodeMap {
+        let mut map = CodeMap::new();
+        let main = map.filename_index("main.basm");
+        let lib = map.filename_index("lib/util.basm");
+        map.add_entry(main, 1);
+        map.add_entry(main, 2);
+        let outer = map.add_expansion("OUTER", main, 3, 5, None);
+        let inner = map.add_expansion("INNER", lib, 10, 1, Some(outer));
+        map.line_entries.push(LineEntry {
+            filename_index: lib,
+            line: 11,
+            expansion: Some(inner),
+        });
+        map.add_entry(main, 4);
+        map
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let map = sample();
+        assert_eq!(CodeMap::from_json(&map.to_json()), Ok(map));
+    }
+
+    #[test]
+    fn round_trips_an_empty_map() {
+        let map = CodeMap::new();
+        assert_eq!(CodeMap::from_json(&map.to_json()), Ok(map));
+    }
+
+    #[test]
+    fn round_trips_awkward_filenames() {
+        let mut map = CodeMap::new();
+        let index = map.filename_index("dir with spaces/\"quoted\"\\ünïcode.basm");
+        map.add_entry(index, 1);
+        assert_eq!(CodeMap::from_json(&map.to_json()), Ok(map));
+    }
+
+    #[test]
+    fn reads_maps_from_before_versioning() {
+        let old = r#"{"filenames":["a.basm"],"line_entries":[{"filename_index":0,"line":7}]}"#;
+        let map = CodeMap::from_json(old).unwrap();
+        assert_eq!(map.version, 0);
+        assert_eq!(
+            map.location(1),
+            Some(Location {
+                filename: "a.basm",
+                line: 7,
+                column: None
+            })
+        );
+        assert!(map.expansions.is_empty());
+    }
+
+    #[test]
+    fn ignores_the_line_columns_of_version_1() {
+        let old = r#"{"version":1,"filenames":["a.basm"],"line_entries":[{"filename_index":0,"line":7,"column":1}]}"#;
+        let map = CodeMap::from_json(old).unwrap();
+        assert_eq!(map.location(1).unwrap().to_string(), "a.basm:7");
+    }
+
+    #[test]
+    fn macro_uses_are_located_to_the_column() {
+        let map = sample();
+        let uses: Vec<String> = map
+            .expansions_of(3)
+            .iter()
+            .map(|e| map.expansion_location(e).to_string())
+            .collect();
+        assert_eq!(uses, ["lib/util.basm:10:1", "main.basm:3:5"]);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut map = sample();
+        map.version = VERSION + 1;
+        assert!(CodeMap::from_json(&map.to_json()).is_err());
+    }
+
+    #[test]
+    fn rejects_dangling_indices() {
+        let mut map = sample();
+        map.add_entry(5, 1);
+        assert!(CodeMap::from_json(&map.to_json()).is_err());
+
+        let mut map = sample();
+        map.expansions[0].parent = Some(1);
+        assert!(CodeMap::from_json(&map.to_json()).is_err());
+    }
+
+    #[test]
+    fn push_reuses_filenames_and_offsets_expansions() {
+        let mut map = CodeMap::new();
+        let top = map.filename_index("top.basm");
+        let lib = map.filename_index("lib/util.basm");
+        map.add_expansion("TOP", top, 1, 1, None);
+        map.add_entry(lib, 1);
+
+        map.push(&sample());
+
+        assert_eq!(map.filenames, ["top.basm", "lib/util.basm", "main.basm"]);
+        assert_eq!(map.expansions.len(), 3);
+        let names: Vec<&str> = map
+            .expansions_of(4)
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect();
+        assert_eq!(names, ["INNER", "OUTER"]);
+        assert_eq!(map.location(4).unwrap().filename, "lib/util.basm");
+        assert_eq!(CodeMap::from_json(&map.to_json()), Ok(map));
+    }
+
+    #[test]
+    fn line_markers_keep_expansions() {
+        let map = sample();
+        let (marked, marked_map) = map.with_line_markers("a\nb\nc\nd");
+
+        assert_eq!(
+            marked,
+            "#line 1 \"main.basm\"\na\nb\n#line 11 \"lib/util.basm\"\nc\n#line 4 \"main.basm\"\nd"
+        );
+        assert_eq!(marked_map.line_entries.len(), marked.lines().count());
+        assert_eq!(marked_map.expansions_of(5), map.expansions_of(3));
+        assert_eq!(CodeMap::from_json(&marked_map.to_json()), Ok(marked_map));
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Names the preprocessor defines itself, unless the source defines them first
+pub const NAMES: [&str; 5] = [
+    "__FILE__",
+    "__LINE__",
+    "__COUNTER__",
+    "__DATE__",
+    "__TIME__",
+];
+
+/// Environment variable that, when set to a number of seconds since 1970, is used as the build
+/// time instead of the current time, so builds can be reproduced exactly
+pub const EPOCH_ENV: &str = "SOURCE_DATE_EPOCH";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    NAMES.contains(&name)
+}
+
+/// Returns the build date as a string literal like `"Oct  8 2026"`, the same as C's __DATE__
+pub fn date() -> Result<String, String> {
+    // Rounding down, so times before 1970 are on the day before rather than the day after
+    let (year, month, day) = civil_date(build_time()?.div_euclid(86400));
+    Ok(format!(
+        "\"{} {:>2} {}\"",
+        MONTHS[month as usize - 1],
+        day,
+        year
+    ))
+}
+
+/// Returns the build time of day as a string literal like `"14:03:09"`, the same as C's __TIME__
+pub fn time() -> Result<String, String> {
+    let seconds = build_time()?.rem_euclid(86400);
+    Ok(format!(
+        "\"{:02}:{:02}:{:02}\"",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    ))
+}
+
+/// Returns the seconds since 1970 the build happened at
+fn build_time() -> Result<i64, String> {
+    match std::env::var(EPOCH_ENV) {
+        Ok(epoch) => epoch.trim().parse::<i64>().map_err(|_| {
+            format!(
+                "{} should be a number of seconds since 1970, found '{}'",
+                EPOCH_ENV, epoch
+            )
+        }),
+        Err(_) => match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(time) => Ok(time.as_secs() as i64),
+            Err(_) => Err("The system clock is set before 1970".to_owned()),
+        },
+    }
+}
+
+/// Turns days since 1970-01-01 into a year, month and day of the (proleptic) Gregorian calendar
+fn civil_date(days: i64) -> (i64, i64, i64) {
+    // Count from 0000-03-01 so leap days fall at the end of each year, in 400 year eras
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{preprocess, Options};
+
+    #[test]
+    fn civil_date_counts_from_1970() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(31), (1970, 2, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(-719468), (0, 3, 1));
+    }
+
+    #[test]
+    fn civil_date_has_leap_days() {
+        assert_eq!(civil_date(11016), (2000, 2, 29));
+        assert_eq!(civil_date(11017), (2000, 3, 1));
+        assert_eq!(civil_date(19782), (2024, 2, 29));
+        assert_eq!(civil_date(47541), (2100, 3, 1)); // 2100 isn't a leap year
+    }
+
+    // The only test that sets SOURCE_DATE_EPOCH, since tests share the environment
+    #[test]
+    fn date_and_time_use_source_date_epoch() {
+        let expand = |epoch: &str| {
+            std::env::set_var(EPOCH_ENV, epoch);
+            let result = preprocess("__DATE__ __TIME__\n", &Options::default());
+            result.map(|(output, _)| output)
+        };
+
+        assert_eq!(expand("0").unwrap(), "\"Jan  1 1970\" \"00:00:00\"\n");
+        assert_eq!(
+            expand("1792245789").unwrap(),
+            "\"Oct 17 2026\" \"14:03:09\"\n"
+        );
+        assert_eq!(expand("-1").unwrap(), "\"Dec 31 1969\" \"23:59:59\"\n");
+        assert!(expand("yesterday").is_err());
+        std::env::remove_var(EPOCH_ENV);
+    }
+}
@@ -0,0 +1,193 @@
+use basm_cli::args::{option_value, parse_define, parse_undef, set_input, short_value};
+use basm_preprocessor::fileio::{Input, Output};
+use basm_preprocessor::term::{ColorChoice, Verbosity};
+
+const USAGE: &str = "\
+Usage: basm-preprocessor [OPTIONS] <FILE>
+
+Arguments:
+  <FILE>                 Source file to preprocess, or `-` to read from stdin
+
+Options:
+  -s                     Read the source from stdin (same as `-`)
+  -o <PATH>              Write the output to PATH, or `-` for stdout [default: out.basm]
+      --map <PATH>       Write the code map to PATH [default: <output>.map, none for stdout]
+      --line-markers     Mark where each line came from with `#line N \"file\"` lines
+                         [default: only when writing to stdout without a map]
+      --no-line-markers  Never write `#line` markers
+      --trace-expansions End each output line with a comment listing the macros expanded on it
+                         and where they were defined
+  -M, --depfile <PATH>   Write a Make dependency file to PATH, saying the output depends on the
+                         source and every file it includes
+  -I <DIR>               Add DIR to the #include search path, which is searched after the
+                         including file's directory and before the directories in
+                         $W4096_INCLUDE
+  -D <NAME>[=<VALUE>]    Define NAME as VALUE (or 1) before preprocessing
+  -U <NAME>              Make sure NAME isn't defined before preprocessing, even if -D defines it
+  -q, --quiet            Only print errors
+  -v, --verbose          Print progress information
+      --color <WHEN>     Color diagnostics: auto, always or never [default: auto]
+  -h, --help             Print this help and exit
+  -V, --version          Print version information and exit";
+
+const DEFAULT_OUTPUT: &str = "out.basm";
+
+/// A fully interpreted command line
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub input: Input,
+    pub output: Output,
+    pub map: Option<String>,
+    pub line_markers: Option<bool>,
+    pub depfile: Option<String>,
+    pub trace_expansions: bool,
+    pub include_dirs: Vec<String>,
+    pub defines: Vec<(String, String)>,
+    pub undefines: Vec<String>,
+    pub verbosity: Verbosity,
+    pub color: ColorChoice,
+}
+
+/// What main should do after the command line has been read
+#[derive(Debug)]
+pub enum Command {
+    Run(Args),
+    Help,
+    Version,
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+pub fn version() -> String {
+    format!("basm-preprocessor {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Interprets command line arguments, not including the program name
+pub fn parse_args<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+
+    let mut input = None;
+    let mut output = Output::File(DEFAULT_OUTPUT.to_owned());
+    let mut map = None;
+    let mut line_markers = None;
+    let mut depfile = None;
+    let mut trace_expansions = false;
+    let mut include_dirs = Vec::new();
+    let mut defines = Vec::new();
+    let mut undefines = Vec::new();
+    let mut verbosity = Verbosity::Normal;
+    let mut color = ColorChoice::Auto;
+    let mut only_positional = false;
+
+    while let Some(arg) = args.next() {
+        if only_positional || arg == "-" || !arg.starts_with('-') {
+            let new_input = if arg == "-" {
+                Input::Stdin
+            } else {
+                Input::File(arg)
+            };
+            set_input(&mut input, new_input)?;
+            continue;
+        }
+
+        // Split `--long=value` so the value can be handled like `--long value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+
+        match flag.as_str() {
+            "--" => only_positional = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-s" => set_input(&mut input, Input::Stdin)?,
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = Verbosity::Verbose,
+            "--map" => map = Some(option_value(&flag, inline_value, &mut args)?),
+            "--line-markers" => line_markers = Some(true),
+            "--no-line-markers" => line_markers = Some(false),
+            "--trace-expansions" => trace_expansions = true,
+            "--depfile" => depfile = Some(option_value(&flag, inline_value, &mut args)?),
+            "--color" => {
+                color = match option_value(&flag, inline_value, &mut args)?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    s => {
+                        return Err(format!(
+                            "Invalid value '{}' for --color (expected auto, always or never)",
+                            s
+                        ))
+                    }
+                }
+            }
+            _ if flag.starts_with("-o") => {
+                output = match short_value("-o", &flag, &mut args)?.as_str() {
+                    "-" => Output::Stdout,
+                    path => Output::File(path.to_owned()),
+                };
+            }
+            _ if flag.starts_with("-M") => depfile = Some(short_value("-M", &flag, &mut args)?),
+            _ if flag.starts_with("-I") => {
+                include_dirs.push(short_value("-I", &flag, &mut args)?);
+            }
+            _ if flag.starts_with("-D") => {
+                defines.push(parse_define(&short_value("-D", &flag, &mut args)?)?);
+            }
+            _ if flag.starts_with("-U") => {
+                undefines.push(parse_undef(&short_value("-U", &flag, &mut args)?)?);
+            }
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    let input = match input {
+        Some(i) => i,
+        None => return Err("Expected an input file, or `-` for stdin".to_owned()),
+    };
+    if depfile.is_some() && matches!(output, Output::Stdout) {
+        return Err(
+            "A dependency file names the output file as its target, so -M can't be used with `-o -`"
+                .to_owned(),
+        );
+    }
+
+    Ok(Command::Run(Args {
+        input,
+        output,
+        map,
+        line_markers,
+        depfile,
+        trace_expansions,
+        include_dirs,
+        defines,
+        undefines,
+        verbosity,
+        color,
+    }))
+}
+
+impl Args {
+    /// Returns where the code map should be written, if anywhere. Unless a path is given, the map
+    /// sits next to the output file, and isn't written at all when the output goes to stdout.
+    pub fn map_path(&self) -> Option<String> {
+        match (&self.map, &self.output) {
+            (Some(path), _) => Some(path.to_owned()),
+            (None, Output::File(path)) => Some(format!("{}.map", path)),
+            (None, Output::Stdout) => None,
+        }
+    }
+
+    /// Returns whether to write `#line` markers. By default they're only written when nothing
+    /// else would keep track of where each line came from.
+    pub fn line_markers(&self) -> bool {
+        self.line_markers.unwrap_or(self.map_path().is_none())
+    }
+}
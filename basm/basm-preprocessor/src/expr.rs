@@ -0,0 +1,208 @@
+/// Part of an #IF expression
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+// Longer operators come first so they're matched before their prefixes
+const OPERATORS: [&str; 22] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")",
+];
+
+/// Binary operators from loosest to tightest binding
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// Evaluates the integer expression of an #IF or #ELIF, once its macros have been replaced.
+/// Names that are left over aren't defined, so they count as 0 like in C.
+pub fn evaluate(text: &str) -> Result<i64, String> {
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Err("Expected an expression".to_owned());
+    }
+
+    let mut parser = ExprParser {
+        tokens,
+        index: 0,
+        skipping: false,
+    };
+    let value = parser.binary(0)?;
+    match parser.tokens.get(parser.index) {
+        None => Ok(value),
+        Some(t) => Err(format!("Unexpected {} in expression", describe(t))),
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !(c == '_' || c.is_alphanumeric()))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c == '_' || c.is_alphabetic() {
+            let end = rest
+                .find(|c: char| !(c == '_' || c.is_alphanumeric()))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_owned()));
+            rest = &rest[end..];
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Operator(op));
+                    rest = &rest[op.len()..];
+                }
+                None => return Err(format!("Unexpected '{}' in expression", c)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a number in any of the bases basm understands
+fn parse_number(text: &str) -> Result<i64, String> {
+    let result = match text.get(0..2) {
+        Some("0x") => i64::from_str_radix(&text[2..], 16),
+        Some("0o") => i64::from_str_radix(&text[2..], 8),
+        Some("0b") => i64::from_str_radix(&text[2..], 2),
+        _ => text.parse::<i64>(),
+    };
+
+    result.map_err(|_| format!("Could not parse number: '{}'", text))
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(n) => format!("number {}", n),
+        Token::Name(name) => format!("'{}'", name),
+        Token::Operator(op) => format!("'{}'", op),
+    }
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    index: usize,
+    skipping: bool, // Whether the value being read is ignored, after && or || has its answer
+}
+
+impl ExprParser {
+    /// Parses binary operators that bind at least as tightly as PRECEDENCE[level]
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Operator(op)) = self.tokens.get(self.index) {
+            let op = *op;
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.index += 1;
+
+            // Like in C, the right of && and || isn't evaluated if the left decides the result, so
+            // `defined(X) && 10 / X > 1` doesn't divide by zero when X isn't defined
+            let decided = (op == "&&" && lhs == 0) || (op == "||" && lhs != 0);
+            let was_skipping = self.skipping;
+            self.skipping |= decided;
+            let rhs = self.binary(level + 1)?;
+            self.skipping = was_skipping;
+
+            // Only mistakes in how the expression is written are reported from a skipped part
+            lhs = match apply(op, lhs, rhs) {
+                _ if decided => (lhs != 0) as i64,
+                Err(_) if self.skipping => 0,
+                result => result?,
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = match self.tokens.get(self.index) {
+            Some(t) => t.clone(),
+            None => return Err("Expected a value at the end of the expression".to_owned()),
+        };
+        self.index += 1;
+
+        match token {
+            Token::Number(n) => Ok(n),
+            Token::Name(_) => Ok(0),
+            Token::Operator("-") => Ok(self.unary()?.wrapping_neg()),
+            Token::Operator("+") => self.unary(),
+            Token::Operator("!") => Ok((self.unary()? == 0) as i64),
+            Token::Operator("~") => Ok(!self.unary()?),
+            Token::Operator("(") => {
+                let value = self.binary(0)?;
+                match self.tokens.get(self.index) {
+                    Some(Token::Operator(")")) => {
+                        self.index += 1;
+                        Ok(value)
+                    }
+                    _ => Err("Expected a ')'".to_owned()),
+                }
+            }
+            t => Err(format!("Expected a value, found {}", describe(&t))),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64) -> Result<i64, String> {
+    let value = match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" | ">>" => {
+            let amount = match u32::try_from(rhs) {
+                Ok(n) if n < 64 => n,
+                _ => return Err(format!("Can't shift by {}", rhs)),
+            };
+            if op == "<<" {
+                lhs.wrapping_shl(amount)
+            } else {
+                lhs.wrapping_shr(amount)
+            }
+        }
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" | "%" if rhs == 0 => return Err("Division by zero".to_owned()),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        _ => unreachable!("{} isn't a binary operator", op),
+    };
+
+    Ok(value)
+}
@@ -1,29 +1,30 @@
-use std::env;
 use std::fs::File;
 use std::io;
 use std::io::prelude::{Read, Write};
-use crate::codemap::CodeMap;
-
-const ASM_FILENAME: &str = "out.basm";
-const MAP_FILENAME: &str = "out.basm.map";
-
-pub fn get_input() -> Result<(String, String), String> {
-    // Get command line arguments
-    let args: Vec<String> = env::args().collect();
-
-    // Interpret arguments
-    match args.len() {
-        1 => return Err("Expected at least one command line argument".to_owned()),
-        2 => match &args[1][0..=1] {
-            "-s" => return get_std(), // -s indicates that the file comes from stdin
-            _ => {}                   // Assume argument is filename and move on
-        },
-        _ => return Err("Too many arguments provided".to_owned()),
-    };
+use std::path::{Path, PathBuf};
+use basm_codemap::CodeMap;
+use crate::term;
 
-    let data = read_file(&args[1])?;
+/// Where the source to be preprocessed comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(String),
+}
 
-    Ok((args[1].to_owned(), data))
+/// Where the preprocessed source is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Stdout,
+    File(String),
+}
+
+/// Reads the source selected on the command line, returning its name and contents
+pub fn get_input(input: &Input) -> Result<(String, String), String> {
+    match input {
+        Input::Stdin => get_std(),
+        Input::File(filename) => Ok((filename.to_owned(), read_file(filename)?)),
+    }
 }
 
 pub fn read_file(filename: &str) -> Result<String, String> {
@@ -53,54 +54,223 @@ pub fn read_file(filename: &str) -> Result<String, String> {
     Ok(data)
 }
 
+/// Returns a name for a file that's the same however the path to it was written, so it can be
+/// recognised when it's included again. Sources that aren't files keep the name they were given.
+pub fn file_identity(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Environment variable holding more directories to search for included files, separated like
+/// PATH is
+pub const INCLUDE_ENV: &str = "W4096_INCLUDE";
+
+/// Finds the file an #include refers to. `#include "path"` is looked for next to the including
+/// file first, while `#include <path>` (a system include) isn't. Both are then looked for in each
+/// of the include directories in order, followed by the directories in W4096_INCLUDE.
+pub fn find_include(
+    path: &str,
+    including_file: &str,
+    include_dirs: &[String],
+    system: bool,
+) -> Result<String, String> {
+    if Path::new(path).is_absolute() {
+        return match Path::new(path).is_file() {
+            true => Ok(path.to_owned()),
+            false => Err(format!("File {} couldn't be found", path)),
+        };
+    }
+
+    let mut candidates = Vec::new();
+    if !system {
+        // Sources that aren't files, like stdin, are treated as being in the current directory
+        let dir = match Path::new(including_file).parent() {
+            Some(dir) if Path::new(including_file).is_file() => dir.to_path_buf(),
+            _ => PathBuf::new(),
+        };
+        candidates.push(dir.join(path));
+    }
+    candidates.extend(include_dirs.iter().map(|dir| Path::new(dir).join(path)));
+    if let Some(dirs) = std::env::var_os(INCLUDE_ENV) {
+        candidates.extend(std::env::split_paths(&dirs).map(|dir| dir.join(path)));
+    }
+
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(found) => Ok(found.to_string_lossy().into_owned()),
+        None if system => Err(format!(
+            "File {} couldn't be found in any include directory or in {}",
+            path, INCLUDE_ENV
+        )),
+        None => Err(format!(
+            "File {} couldn't be found next to {}, in any include directory or in {}",
+            path, including_file, INCLUDE_ENV
+        )),
+    }
+}
+
 fn get_std() -> Result<(String, String), String> {
     let stdin = io::stdin();
     let mut data = String::new();
     match stdin.lock().read_to_string(&mut data) {
-        Ok(n) => println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {n} bytes read from stdin."),
+        Ok(n) => term::info(&format!("{n} bytes read from stdin.")),
         Err(e) => return Err(format!("Couldn't read from stdin, error:\n  {}", e)),
     }
     Ok(("stdin".to_owned(), data)) // Return read file plus stdin "filename"
 }
 
-pub fn write_asm_file(data: &str) -> Result<(), String> {
-    let mut file = match File::create(ASM_FILENAME) {
-        Ok(f) => f,
-        Err(e) => return Err(format!(
-            "{} couldn't be created. File::create(...) returned the following error:\n  {}",
-            ASM_FILENAME,
-            e,
-        )),
+/// Writes the preprocessed source to the chosen output
+pub fn write_asm_file(output: &Output, data: &str) -> Result<(), String> {
+    let filename = match output {
+        Output::File(f) => f,
+        Output::Stdout => {
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout.write_all(data.as_bytes()).and_then(|_| stdout.flush()) {
+                return Err(format!("Couldn't write to stdout, error:\n  {}", e));
+            }
+            return Ok(());
+        }
     };
 
-    if let Err(e) = file.write_all(data.as_bytes()) {
-        return Err(format!(
-            "{} couldn't be written to. file.write_all(...) returned the following error:\n  {}",
-            MAP_FILENAME,
-            e,
-        ));
-    };
+    write_file(filename, data.as_bytes())
+}
 
-    Ok(())
+pub fn write_map_file(filename: &str, data: &CodeMap) -> Result<(), String> {
+    write_file(filename, data.to_json().as_bytes())
+}
+
+/// Writes a dependency file Make and Ninja can read, saying target has to be rebuilt when the
+/// source or any file it included changes. Each included file also gets a rule of its own, so
+/// Make doesn't stop when one of them is deleted.
+pub fn write_dep_file(
+    filename: &str,
+    target: &str,
+    source: Option<&str>,
+    includes: &[String],
+) -> Result<(), String> {
+    let mut data = format!("{}:", escape_make_path(target));
+    for dependency in source.into_iter().chain(includes.iter().map(String::as_str)) {
+        data.push_str(" \\\n  ");
+        data.push_str(&escape_make_path(dependency));
+    }
+    data.push('\n');
+    for include in includes {
+        data.push_str(&format!("\n{}:\n", escape_make_path(include)));
+    }
+
+    write_file(filename, data.as_bytes())
+}
+
+/// Escapes the characters that mean something else in a Makefile rule
+fn escape_make_path(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace('#', "\\#")
+        .replace(' ', "\\ ")
 }
 
-pub fn write_map_file(data: &CodeMap) -> Result<(), String> {
-    let mut file = match File::create(MAP_FILENAME) {
+fn write_file(filename: &str, data: &[u8]) -> Result<(), String> {
+    let mut file = match File::create(filename) {
         Ok(f) => f,
         Err(e) => return Err(format!(
             "{} couldn't be created. File::create(...) returned the following error:\n  {}",
-            ASM_FILENAME,
+            filename,
             e,
         )),
     };
 
-    if let Err(e) = file.write_all(&serde_json::to_string(data).unwrap().as_bytes()) {
+    if let Err(e) = file.write_all(data) {
         return Err(format!(
             "{} couldn't be written to. file.write_all(...) returned the following error:\n  {}",
-            MAP_FILENAME,
+            filename,
             e,
         ));
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Makes an empty directory of its own for a test to write files into
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "basm-preprocessor-fileio-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn finds_includes_next_to_the_file_then_in_include_dirs() {
+        let dir = temp_dir("search-order");
+        for (subdir, files) in [
+            ("src", &["main.basm", "lib.basm"][..]),
+            ("first", &["lib.basm", "both.basm"]),
+            ("second", &["lib.basm", "both.basm", "second.basm"]),
+        ] {
+            fs::create_dir_all(dir.join(subdir)).unwrap();
+            for file in files {
+                fs::write(dir.join(subdir).join(file), "").unwrap();
+            }
+        }
+        let path = |parts: &[&str]| {
+            let path = parts.iter().fold(dir.clone(), |path, part| path.join(part));
+            path.to_string_lossy().into_owned()
+        };
+        let main = path(&["src", "main.basm"]);
+        let include_dirs = [path(&["first"]), path(&["second"])];
+        let find = |name: &str, system: bool| find_include(name, &main, &include_dirs, system);
+
+        assert_eq!(find("lib.basm", false), Ok(path(&["src", "lib.basm"])));
+        assert_eq!(find("lib.basm", true), Ok(path(&["first", "lib.basm"])));
+        assert_eq!(find("both.basm", false), Ok(path(&["first", "both.basm"])));
+        assert_eq!(
+            find("second.basm", false),
+            Ok(path(&["second", "second.basm"]))
+        );
+        assert!(find("main.basm", true).is_err());
+        assert!(find("missing.basm", false).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn escapes_what_make_would_read_differently() {
+        assert_eq!(escape_make_path("my file.basm"), "my\\ file.basm");
+        assert_eq!(escape_make_path("$HOME/a#b"), "$$HOME/a\\#b");
+        assert_eq!(escape_make_path("plain/path.basm"), "plain/path.basm");
+    }
+
+    #[test]
+    fn dep_files_list_the_source_and_every_include() {
+        let dir = temp_dir("depfile");
+        let depfile = dir.join("out.d").to_string_lossy().into_owned();
+        let includes = ["lib/a b.basm".to_owned(), "$x.basm".to_owned()];
+        write_dep_file(&depfile, "out.basm", Some("main.basm"), &includes).unwrap();
+        assert_eq!(
+            fs::read_to_string(&depfile).unwrap(),
+            "out.basm: \\\n  main.basm \\\n  lib/a\\ b.basm \\\n  $$x.basm\n\nlib/a\\ b.basm:\n\n$$x.basm:\n"
+        );
+
+        // Stdin has no file to depend on
+        write_dep_file(&depfile, "out.basm", None, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&depfile).unwrap(), "out.basm:\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Tests run in the package's directory, which has Cargo.toml in it
+    #[test]
+    fn stdin_includes_from_the_current_directory() {
+        assert_eq!(
+            find_include("Cargo.toml", "stdin", &[], false),
+            Ok("Cargo.toml".to_owned())
+        );
+        assert!(find_include("Cargo.toml", "stdin", &[], true).is_err());
+    }
+}
@@ -1,13 +1,29 @@
+use crate::term;
+
 #[derive(Debug, Clone)]
 pub enum TokenKind {
-    Code(String), // For raw code
-    Newline,      // \n
-    Whitespace,   // Spaces
-    None,         // For things that should be ignored
-    Include,      // For including other asm files
-    Define,       // For defining constants
-    Undef,        // For undefining constants
+    Code(String),       // For raw code
+    Newline,            // \n
+    Whitespace(String), // Spaces and tabs, as written
+    Comment(String),    // From a ; to the end of the line, as written
+    None,               // For things that should be ignored
+    Include,            // For including other asm files
+    Define,             // For defining constants
+    Undef,              // For undefining constants
+    Macro,              // For starting a block macro
+    EndMacro,           // For ending a block macro
+    IfDef,              // For keeping lines if a name is defined
+    IfNDef,             // For keeping lines if a name isn't defined
+    If,                 // For keeping lines if an expression isn't 0
+    Elif,               // For trying another condition if the ones before weren't met
+    Else,               // For keeping lines if none of the conditions were met
+    EndIf,              // For ending a conditional
+    Pragma,             // For instructions to the preprocessor itself, like `#pragma once`
+    Error,              // For stopping with an error
+    Warning,            // For printing a warning
+    Message,            // For printing a message
     String(String),
+    NoExpand(String), // A name found inside its own expansion, which is never replaced again
 }
 
 #[derive(Debug, Clone)]
@@ -53,17 +69,28 @@ fn skip_white_space(data: &str) -> usize {
     }
 }
 
-/// Returns the length of a span from a ; to a newline
-fn skip_comment(data: &str) -> usize {
-    if data.starts_with(';') {
-        let bytes_read = match take_while(data, |c| c != '\n') {
-            Ok((_, bytes_read)) => bytes_read,
-            Err(_) => panic!("Unexpected EOF in skip_comment"),
-        };
-        return bytes_read;
+/// Returns the span from a ; to a newline
+fn take_comment(data: &str) -> &str {
+    match take_while(data, |c| c != '\n') {
+        Ok((comment, _)) => comment,
+        Err(_) => "",
     }
+}
 
-    0
+/// Writes text as a string literal that reads back as the same text, escaping what has to be
+pub fn string_literal(text: &str) -> String {
+    let mut literal = String::from('"');
+    for c in text.chars() {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\0' => literal.push_str("\\0"),
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
 }
 
 /// Returns a String from the 2nd char of data to the next ", will break if there's no "
@@ -97,10 +124,6 @@ fn tokenize_string_literal(data: &str) -> Result<Token, String> {
         final_string.push(next);
     }
 
-    if bytes_read == 0 {
-        return Err("No matches".to_owned());
-    }
-
     Ok(Token {
         kind: TokenKind::String(final_string),
         span: bytes_read + 2,
@@ -115,22 +138,53 @@ fn tokenize_word(data: &str) -> Result<Token, String> {
     })
 }
 
+/// Takes a single character of punctuation, so names right next to it (like in `(TABLE + ix)` or
+/// `PUSH16(ac)`) are still tokens of their own
 fn tokenize_other(data: &str) -> Result<Token, String> {
-    let (read, bytes_read) = take_while(data, |c| !c.is_whitespace())?;
+    let c = match data.chars().next() {
+        Some(c) => c,
+        None => return Err("No matches".to_owned()),
+    };
     Ok(Token {
-        kind: TokenKind::Code(read.to_owned()),
-        span: bytes_read,
+        kind: TokenKind::Code(c.to_string()),
+        span: c.len_utf8(),
     })
 }
 
-/// Tokenizes a single directive
-fn tokenize_directive(data: &str) -> Result<Token, String> {
+/// Tokenizes a single directive. Away from the start of a line, a '#' that doesn't start one is
+/// a macro's `#param` or `a ## b` operator instead.
+fn tokenize_directive(data: &str, line_start: bool) -> Result<Token, String> {
+    if data.starts_with("##") {
+        return Ok(Token {
+            kind: TokenKind::Code("##".to_owned()),
+            span: 2,
+        });
+    }
+
     let (read, bytes_read) = take_while(data, |c| c == '_' || c == '#' || c.is_alphanumeric())?;
 
     let token_kind = match &read.to_lowercase()[..] {
         "#include" => TokenKind::Include,
         "#define" => TokenKind::Define,
         "#undef" => TokenKind::Undef,
+        "#macro" => TokenKind::Macro,
+        "#endmacro" => TokenKind::EndMacro,
+        "#ifdef" => TokenKind::IfDef,
+        "#ifndef" => TokenKind::IfNDef,
+        "#if" => TokenKind::If,
+        "#elif" => TokenKind::Elif,
+        "#else" => TokenKind::Else,
+        "#endif" => TokenKind::EndIf,
+        "#pragma" => TokenKind::Pragma,
+        "#error" => TokenKind::Error,
+        "#warning" => TokenKind::Warning,
+        "#message" => TokenKind::Message,
+        _ if !line_start => {
+            return Ok(Token {
+                kind: TokenKind::Code("#".to_owned()),
+                span: 1,
+            })
+        }
         s => return Err(format!("Unknown preprocessor directive '{}'.", s)),
     };
 
@@ -159,25 +213,32 @@ impl Lexer {
     pub fn tokenize(&mut self) -> Result<(), String> {
         while self.span.0 < self.span.1 {
             let (kind, span) = match self
-                .data
+                .get_selected()
                 .chars()
-                .nth(self.span.0)
+                .next()
                 .unwrap_or_else(|| panic!("Lexer object span broke. Did you forget a '\"'?\n"))
             {
                 c if c.is_whitespace() && c != '\n' => {
-                    (TokenKind::Whitespace, skip_white_space(self.get_selected()))
+                    let span = skip_white_space(self.get_selected());
+                    let text = self.get_selected()[..span].to_owned();
+                    (TokenKind::Whitespace(text), span)
                 }
                 '\n' => {
                     self.line += 1;
                     (TokenKind::Newline, 1)
                 }
-                ';' => (TokenKind::None, skip_comment(self.get_selected())),
+                ';' => {
+                    let comment = take_comment(self.get_selected()).to_owned();
+                    let span = comment.len();
+                    (TokenKind::Comment(comment), span)
+                }
                 _ => match self.tokenize_one_token() {
                     Ok(tok) => (tok.kind, tok.span),
                     Err(e) => {
                         return Err(format!(
-                            "\x1b[91mError on line {} of {}:\x1b[0m\n  {}",
-                            self.line, self.filename, e
+                            "{}\n  {}",
+                            term::error_at(&self.filename, self.line),
+                            e
                         ))
                     }
                 },
@@ -200,13 +261,22 @@ impl Lexer {
         };
 
         match next {
-            '#' => tokenize_directive(data),
+            '#' => tokenize_directive(data, self.at_line_start()),
             '"' => tokenize_string_literal(data),
-            c if c.is_alphanumeric() => tokenize_word(data),
+            c if c == '_' || c.is_alphanumeric() => tokenize_word(data),
             _ => tokenize_other(data),
         }
     }
 
+    /// Returns whether there's nothing but whitespace before the next token on its line
+    fn at_line_start(&self) -> bool {
+        self.tokens
+            .iter()
+            .rev()
+            .take_while(|t| !matches!(t.kind, TokenKind::Newline))
+            .all(|t| matches!(t.kind, TokenKind::Whitespace(_)))
+    }
+
     fn consume(&mut self, amount: usize) {
         let (start, end) = self.span;
         self.span = (start + amount, end);
@@ -221,13 +291,26 @@ impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match &self.kind {
             TokenKind::Code(s) => write!(f, "{}", s),
-            TokenKind::Newline => write!(f, "\n"),
-            TokenKind::Whitespace => write!(f, " "),
+            TokenKind::Newline => writeln!(f),
+            TokenKind::Whitespace(s) | TokenKind::Comment(s) => write!(f, "{}", s),
             TokenKind::None => Ok(()),
             TokenKind::Include => write!(f, "#INCLUDE"),
             TokenKind::Define => write!(f, "#DEFINE"),
             TokenKind::Undef => write!(f, "#UNDEF"),
-            TokenKind::String(s) => write!(f, r#""{}""#, s),
+            TokenKind::Macro => write!(f, "#MACRO"),
+            TokenKind::EndMacro => write!(f, "#ENDMACRO"),
+            TokenKind::IfDef => write!(f, "#IFDEF"),
+            TokenKind::IfNDef => write!(f, "#IFNDEF"),
+            TokenKind::If => write!(f, "#IF"),
+            TokenKind::Elif => write!(f, "#ELIF"),
+            TokenKind::Else => write!(f, "#ELSE"),
+            TokenKind::EndIf => write!(f, "#ENDIF"),
+            TokenKind::Pragma => write!(f, "#PRAGMA"),
+            TokenKind::Error => write!(f, "#ERROR"),
+            TokenKind::Warning => write!(f, "#WARNING"),
+            TokenKind::Message => write!(f, "#MESSAGE"),
+            TokenKind::String(s) => write!(f, "{}", string_literal(s)),
+            TokenKind::NoExpand(s) => write!(f, "{}", s),
         }
     }
 }
@@ -0,0 +1,63 @@
+pub mod builtins;
+pub mod expr;
+pub mod fileio;
+pub mod lexer;
+pub mod parser;
+pub mod term;
+
+pub use basm_codemap::CodeMap;
+
+use lexer::Lexer;
+use parser::Parser;
+
+/// Settings for a preprocessor run
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub filename: String, // What to call the source in messages and the code map
+    pub include_dirs: Vec<String>,
+    pub defines: Vec<(String, String)>, // Constants to define before the source is processed
+    pub undefines: Vec<String>,         // Names to leave undefined, even if they're in defines
+    pub trace_expansions: bool, // Whether to end each line with a comment saying what was expanded on it
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            filename: "stdin".to_owned(),
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            undefines: Vec::new(),
+            trace_expansions: false,
+        }
+    }
+}
+
+/// Preprocesses source, returning the output and a code map saying which file and line each of
+/// its lines came from
+pub fn preprocess(source: &str, options: &Options) -> Result<(String, CodeMap), String> {
+    let (output, map, _) = preprocess_with_dependencies(source, options)?;
+    Ok((output, map))
+}
+
+/// Preprocesses source like preprocess, also returning every file it included
+pub fn preprocess_with_dependencies(
+    source: &str,
+    options: &Options,
+) -> Result<(String, CodeMap, Vec<String>), String> {
+    let mut lexer = Lexer::new(&options.filename, source.to_owned());
+    lexer.tokenize()?;
+
+    let mut parser = Parser::new(&options.filename, lexer.tokens.as_slice());
+    parser.env.include_dirs = options.include_dirs.clone();
+    parser.env.trace = options.trace_expansions;
+    for (name, value) in &options.defines {
+        parser.predefine(name, value)?;
+    }
+    for name in &options.undefines {
+        parser.env.deflist.remove(name);
+    }
+    parser.parse()?;
+    parser.write_trace(); // For the last line, which has no newline to end it
+
+    Ok((parser.output, parser.map, parser.env.dependencies))
+}
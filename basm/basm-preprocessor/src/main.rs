@@ -1,54 +1,84 @@
-mod codemap;
-mod fileio;
-mod lexer;
-mod parser;
+mod cli;
 
-fn main() {
-    // Get input data
-    let (filename, program) = match fileio::get_input() {
-        Ok(s) => s,
+use basm_preprocessor::{fileio, term};
+use std::process::ExitCode;
+
+const EXIT_FAILURE: u8 = 1; // Something went wrong while preprocessing
+const EXIT_USAGE: u8 = 2; // The command line couldn't be understood
+
+fn main() -> ExitCode {
+    // Interpret command line arguments
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(args)) => args,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return ExitCode::SUCCESS;
+        }
+        Ok(cli::Command::Version) => {
+            println!("{}", cli::version());
+            return ExitCode::SUCCESS;
+        }
         Err(e) => {
-            println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {}", e);
-            return;
+            term::set_color(term::ColorChoice::Auto);
+            term::error(&format!("{}\n\n{}", e, cli::usage()));
+            return ExitCode::from(EXIT_USAGE);
         }
     };
 
-    // Create lexer from input data and convert it into smaller parts for processing
-    let mut lexer = lexer::Lexer::new(&filename, program);
+    term::set_color(args.color);
+    term::set_verbosity(args.verbosity);
 
-    match lexer.tokenize() {
-        Ok(()) => {}
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {}", e);
-            return;
+            term::error(&e);
+            ExitCode::from(EXIT_FAILURE)
         }
     }
+}
 
-    // Create parser from the output of the lexer, then process the data (resolve consts and includes, etc)
-    let mut parser = parser::Parser::new(&filename, lexer.tokens.as_slice());
+fn run(args: &cli::Args) -> Result<(), String> {
+    // Get input data
+    let (filename, program) = fileio::get_input(&args.input)?;
 
-    match parser.parse() {
-        Ok(()) => {}
-        Err(e) => {
-            println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {}", e);
-            return;
-        }
-    }
+    // Resolve consts and includes, etc
+    let options = basm_preprocessor::Options {
+        filename,
+        include_dirs: args.include_dirs.clone(),
+        defines: args.defines.clone(),
+        undefines: args.undefines.clone(),
+        trace_expansions: args.trace_expansions,
+    };
+    let (output, map, includes) =
+        basm_preprocessor::preprocess_with_dependencies(&program, &options)?;
+
+    // Mark where lines came from in the output itself if asked to
+    let (output, map) = if args.line_markers() {
+        map.with_line_markers(&output)
+    } else {
+        (output, map)
+    };
 
     // Write output to files
-    match fileio::write_asm_file(&parser.output){
-        Ok(()) => {}
-        Err(e) => {
-            println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {}", e);
-            return;
-        }
+    fileio::write_asm_file(&args.output, &output)?;
+    if let fileio::Output::File(path) = &args.output {
+        term::info(&format!("Output written to {}", path));
     }
 
-    match fileio::write_map_file(&parser.map) {
-        Ok(()) => {}
-        Err(e) => {
-            println!("\x1b[95mBASM-PREPROCESSOR:\x1b[0m {}", e);
-            return;
-        }
+    if let Some(path) = args.map_path() {
+        fileio::write_map_file(&path, &map)?;
+        term::info(&format!("Code map written to {}", path));
     }
+
+    // The command line makes sure there's an output file to name as the target
+    if let (Some(path), fileio::Output::File(target)) = (&args.depfile, &args.output) {
+        let source = match &args.input {
+            fileio::Input::File(source) => Some(source.as_str()),
+            fileio::Input::Stdin => None,
+        };
+        fileio::write_dep_file(path, target, source, &includes)?;
+        term::info(&format!("Dependency file written to {}", path));
+    }
+
+    Ok(())
 }
@@ -1,16 +1,74 @@
-use crate::codemap::CodeMap;
-use crate::fileio::read_file;
-use crate::lexer::{Lexer, Token, TokenKind};
-use std::collections::HashMap;
+use crate::builtins;
+use crate::expr;
+use crate::fileio::{file_identity, find_include, read_file};
+use crate::lexer::{string_literal, Lexer, Token, TokenKind};
+use crate::term;
+use basm_cli::args::is_name;
+use basm_codemap::{CodeMap, LineEntry};
+use std::collections::{HashMap, HashSet};
+
+/// How many macros can be expanded inside each other before it's assumed they'll never stop
+const MAX_EXPANSION_DEPTH: usize = 100;
 
 pub struct Parser<'a> {
     tokens: &'a [Token],
     pub output: String,
     pub map: CodeMap,
-    pub deflist: HashMap<String, (usize, usize)>,
+    pub env: Environment,
     index: usize,
     line: usize,
     filename: String,
+    expansion: Option<usize>, // The expansion in the code map this parser's output is part of
+    call_site: Option<(String, usize)>, // Where the outermost macro being expanded was used
+    conditionals: Vec<Conditional>, // The conditionals the current line is inside, innermost last
+}
+
+/// An #IF, #IFDEF or #IFNDEF that hasn't reached its #ENDIF yet
+#[derive(Debug, Clone, Copy)]
+struct Conditional {
+    line: usize,     // Where it started, for errors
+    active: bool,    // Whether lines in the current branch are kept
+    taken: bool,     // Whether a branch has been kept already, so no later one can be
+    seen_else: bool, // Whether the #ELSE has been reached, so no more branches can follow
+}
+
+/// What every parser working on the same program shares, including the ones for included files
+/// and macro expansions. Each of them is lent it in turn, and hands it back when it's done.
+#[derive(Debug, Default)]
+pub struct Environment {
+    pub deflist: HashMap<String, Macro>, // Maps each defined name to what replaces it
+    pub include_dirs: Vec<String>,
+    pub expansion_count: usize, // How many block macros have been expanded, for making labels unique
+    pub includes: Vec<(String, String)>, // Identity and name of each file being parsed, outermost first
+    pub once: HashSet<String>,           // The identities of files marked with `#pragma once`
+    pub counter: usize,                  // How many times __COUNTER__ has been used
+    pub expanding: Vec<String>,          // The macros being expanded, outermost first
+    pub runaway: bool, // Whether expansion went too deep, so the error shouldn't be added to
+    pub dependencies: Vec<String>, // Every file found for an #INCLUDE, in the order first found
+    pub defined_at: HashMap<String, (String, usize)>, // The file and line each name was defined on
+    pub trace: bool,   // Whether to note which macros were expanded on each line
+    pub trace_notes: Vec<String>, // The macros expanded on the current line, when tracing
+}
+
+/// What a defined name is replaced with
+#[derive(Debug, Clone)]
+pub enum Macro {
+    Constant(Vec<Token>), // `#DEFINE NAME body`
+    Function {
+        // `#DEFINE NAME(params) body`, used as `NAME(args)`
+        params: Vec<String>,
+        body: Vec<Token>,
+    },
+    Block(Block), // `#MACRO NAME params` up to `#ENDMACRO`, used as `NAME args`
+}
+
+/// A macro spanning several lines, which is written out in place of the line using it
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub params: Vec<String>,
+    pub body: Vec<Token>,
+    pub filename: String,
+    pub line: usize, // Where the body starts in filename
 }
 
 impl<'a> Parser<'a> {
@@ -19,28 +77,64 @@ impl<'a> Parser<'a> {
             tokens,
             output: String::new(),
             map: CodeMap::new(),
-            deflist: HashMap::new(),
+            env: Environment::default(),
             index: 0,
             line: 1,
             filename: filename.to_owned(),
+            expansion: None,
+            call_site: None,
+            conditionals: Vec::new(),
         }
     }
 
     pub fn parse(&mut self) -> Result<(), String> {
         self.map.filenames.push(self.filename.to_owned());
-        self.map.add_entry(0, self.line);
-        loop {
-            match self.parse_single_expr() {
-                Ok(Some(())) => {}
-                Ok(None) => break,
-                Err(e) => {
-                    return Err(format!(
-                        "\x1b[91mError on line {} of {}:\x1b[0m\n  {}",
-                        self.line, self.map.filenames[0], e,
-                    ))
-                }
-            }
+        self.env
+            .includes
+            .push((file_identity(&self.filename), self.filename.to_owned()));
+        let result = self.parse_lines();
+        self.env.includes.pop();
+
+        result.map_err(|e| {
+            format!(
+                "{}\n  {}",
+                term::error_at(&self.map.filenames[0], self.line),
+                e,
+            )
+        })
+    }
+
+    /// Processes tokens that each make up lines of output, adding code map entries for them
+    fn parse_lines(&mut self) -> Result<(), String> {
+        self.add_line_entry();
+        while self.parse_single_expr()?.is_some() {}
+
+        match self.conditionals.last() {
+            Some(c) => Err(format!(
+                "The conditional started on line {} is missing its #ENDIF",
+                c.line
+            )),
+            None => Ok(()),
         }
+    }
+
+    /// Defines a constant from outside of the source, e.g. from a -D command line argument
+    pub fn predefine(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let mut lexer = Lexer::new("<command line>", value.to_owned());
+        lexer.tokenize()?;
+        self.env
+            .deflist
+            .insert(name.to_owned(), Macro::Constant(lexer.tokens));
+        self.env
+            .defined_at
+            .insert(name.to_owned(), ("<command line>".to_owned(), 1));
+        Ok(())
+    }
+
+    /// Processes tokens that don't correspond to any lines of the original file, like the body of
+    /// a constant, so no code map entries are added
+    fn parse_replacement(&mut self) -> Result<(), String> {
+        while self.parse_single_expr()?.is_some() {}
         Ok(())
     }
 
@@ -51,64 +145,300 @@ impl<'a> Parser<'a> {
             None => return Ok(None),
         };
 
+        // Lines in a branch that isn't taken are dropped, apart from the conditionals in them
+        let conditional = matches!(
+            tok.kind,
+            TokenKind::IfDef
+                | TokenKind::IfNDef
+                | TokenKind::If
+                | TokenKind::Elif
+                | TokenKind::Else
+                | TokenKind::EndIf
+        );
+        if !self.active() && !conditional && !matches!(tok.kind, TokenKind::Newline) {
+            self.next();
+            return Ok(Some(()));
+        }
+
         match &tok.kind {
             TokenKind::Newline => {
+                self.write_trace();
                 self.output.push('\n');
                 self.line += 1;
                 self.next();
-                self.map.add_entry(0, self.line);
+                self.add_line_entry();
             }
-            TokenKind::Whitespace => {
-                self.output.push(' ');
+            TokenKind::Whitespace(s) | TokenKind::Comment(s) => {
+                self.output.push_str(&s.to_owned());
                 self.next();
             }
             TokenKind::Code(d) => {
-                if let Some(param_span) = self.deflist.get(d) {
-                    let param_span = *param_span;
-                    let prev_index = self.index; // Save previous state
-                                                 // Add one as to not include the label from the #DEFINE
-                    self.index = param_span.0 + 1;
-
-                    while self.index <= param_span.1 {
-                        match self.parse_single_expr() {
-                            Ok(Some(())) => {}
-                            Ok(None) => break,
-                            Err(e) => {
-                                return Err(format!("Failed replacing label. {e}"));
-                            }
+                let d = d.to_owned();
+                let column = self.column();
+                self.next();
+
+                match self.env.deflist.get(&d).cloned() {
+                    Some(Macro::Constant(body)) => {
+                        let body = hide(&body, &[], &self.hidden(&d));
+                        self.expand(&d, &paste(&d, &body)?)?;
+                    }
+                    Some(Macro::Function { params, body }) => match self.macro_args()? {
+                        Some(args) => {
+                            let body = hide(&body, &params, &self.hidden(&d));
+                            self.expand(&d, &substitute(&d, &params, &args, &body)?)?;
                         }
+                        None => self.output.push_str(&d), // Not called, so just a name like any other
+                    },
+                    Some(Macro::Block(block)) => {
+                        let args = self.line_args();
+                        self.expand_block(&d, column, &block, args)?;
                     }
-
-                    self.index = prev_index + 1;
-                } else {
-                    let d = &d.to_owned();
-                    self.output.push_str(d);
-                    self.next();
+                    None if builtins::is_builtin(&d) => {
+                        let replacement = self.builtin(&d)?;
+                        self.output.push_str(&replacement);
+                    }
+                    None => self.output.push_str(&d),
                 }
             }
+            TokenKind::NoExpand(d) => {
+                self.output.push_str(&d.to_owned());
+                self.next();
+            }
             TokenKind::String(d) => {
-                let d = &d.to_owned();
-                self.output.push('"');
-                self.output.push_str(d);
-                self.output.push('"');
+                self.output.push_str(&string_literal(d));
                 self.next();
             }
-            TokenKind::Include | TokenKind::Define | TokenKind::Undef => {
+            TokenKind::Include
+            | TokenKind::Define
+            | TokenKind::Undef
+            | TokenKind::Macro
+            | TokenKind::Pragma
+            | TokenKind::Error
+            | TokenKind::Warning
+            | TokenKind::Message => {
                 self.parse_directive()?;
             }
+            TokenKind::EndMacro => return Err("Found an #ENDMACRO without a #MACRO".to_owned()),
+            _ if conditional => self.parse_conditional()?,
             TokenKind::None => {}
+            _ => unreachable!(),
         }
 
         Ok(Some(()))
     }
 
+    /// Processes a macro's replacement on its own, with access to every name defined so far
+    fn expand(&mut self, name: &str, replacement: &[Token]) -> Result<(), String> {
+        self.trace(name);
+        self.enter_expansion(name)?;
+        let result = self.expand_to_string(replacement);
+        self.env.expanding.pop();
+
+        self.output.push_str(&result?);
+        Ok(())
+    }
+
+    /// Notes that a macro was expanded on the current line, if expansions are being traced
+    fn trace(&mut self, name: &str) {
+        if !self.env.trace {
+            return;
+        }
+        let mut note = match self.env.defined_at.get(name) {
+            Some((filename, line)) => format!("{} from {}:{}", name, filename, line),
+            None => name.to_owned(),
+        };
+        if let Some(outer) = self.env.expanding.last() {
+            note.push_str(&format!(" in {}", outer));
+        }
+        self.env.trace_notes.push(note);
+    }
+
+    /// Ends the current line of output with a comment listing the macros expanded on it, if
+    /// expansions are being traced. Lines written by a block macro get their own comments.
+    pub fn write_trace(&mut self) {
+        if !self.env.trace_notes.is_empty() {
+            let notes = std::mem::take(&mut self.env.trace_notes);
+            self.output
+                .push_str(&format!(" ; expanded {}", notes.join(", ")));
+        }
+    }
+
+    /// Notes that a macro is being expanded, failing if macros have been expanded inside each
+    /// other so many times that it looks like they'll never stop
+    fn enter_expansion(&mut self, name: &str) -> Result<(), String> {
+        if self.env.expanding.len() >= MAX_EXPANSION_DEPTH {
+            self.env.runaway = true;
+            return Err(format!(
+                "Expanding '{}' went more than {} macros deep, so it probably never ends. \
+                Each macro was used in the one before it:\n    {}",
+                name,
+                MAX_EXPANSION_DEPTH,
+                describe_chain(&self.env.expanding)
+            ));
+        }
+        self.env.expanding.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Returns the names that shouldn't be replaced in a macro's replacement, which are those of
+    /// the #DEFINEs it's part of, including itself. Block macros can use themselves, as an #IF can
+    /// stop them.
+    fn hidden(&self, name: &str) -> Vec<String> {
+        self.env
+            .expanding
+            .iter()
+            .map(String::as_str)
+            .chain([name])
+            .filter(|n| !matches!(self.env.deflist.get(*n), Some(Macro::Block(_))))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Returns what tokens turn into once every macro in them has been replaced
+    fn expand_to_string(&mut self, tokens: &[Token]) -> Result<String, String> {
+        let mut parser = self.child(&self.filename.to_owned(), tokens);
+        parser.call_site = Some(self.location());
+        let result = parser.parse_replacement();
+        self.reclaim(&mut parser);
+
+        match result {
+            Ok(()) => Ok(parser.output),
+            Err(e) if self.env.runaway => Err(e),
+            Err(e) => Err(format!("Failed replacing label. {e}")),
+        }
+    }
+
+    /// Writes out a block macro in place of the line using it. Its lines are mapped to the macro's
+    /// body, as part of an expansion pointing back at where it was used.
+    fn expand_block(
+        &mut self,
+        name: &str,
+        column: usize,
+        block: &Block,
+        args: Vec<Vec<Token>>,
+    ) -> Result<(), String> {
+        let body = localize_labels(&block.body, &block.params, self.env.expansion_count);
+        self.env.expansion_count += 1;
+        let body = hide(&body, &block.params, &self.hidden(name));
+        let body = substitute(name, &block.params, &args, &body)?;
+
+        self.trace(name);
+        self.enter_expansion(name)?;
+        let mut parser = self.child(&block.filename, body.as_slice());
+        parser.call_site = Some(self.location());
+        parser.line = block.line;
+        parser.map.filenames.push(block.filename.to_owned());
+        let call_file = parser.map.filename_index(&self.filename);
+        parser.expansion = Some(
+            parser
+                .map
+                .add_expansion(name, call_file, self.line, column, None),
+        );
+
+        let result = parser.parse_lines();
+        self.reclaim(&mut parser);
+        self.env.expanding.pop();
+
+        if let Err(e) = result {
+            if self.env.runaway {
+                return Err(e);
+            }
+            return Err(format!(
+                "In expansion of '{}', line {} of {}:\n  {}",
+                name, parser.line, block.filename, e
+            ));
+        }
+        self.output.push_str(&parser.output);
+        self.push_map(&parser.map);
+        Ok(())
+    }
+
+    /// Makes a parser for other tokens that's lent this one's environment, which has to be given
+    /// back with reclaim once it's done
+    fn child<'b>(&mut self, filename: &str, tokens: &'b [Token]) -> Parser<'b> {
+        let mut parser = Parser::new(filename, tokens);
+        parser.env = std::mem::take(&mut self.env);
+        parser
+    }
+
+    fn reclaim(&mut self, child: &mut Parser) {
+        self.env = std::mem::take(&mut child.env);
+    }
+
+    /// Adds the code map of output written by another parser, which starts on the current line
+    fn push_map(&mut self, map: &CodeMap) {
+        let entries = self.map.line_entries.len().saturating_sub(1);
+        let expansions = self.map.expansions.len();
+
+        // The other output's first line is written on the current line, so its entry replaces
+        // the current line's entry
+        self.map.line_entries.pop();
+        self.map.push(map);
+
+        // Anything written from inside an expansion is part of it too
+        if let Some(outer) = self.expansion {
+            for entry in &mut self.map.line_entries[entries..] {
+                entry.expansion.get_or_insert(outer);
+            }
+            for expansion in &mut self.map.expansions[expansions..] {
+                expansion.parent.get_or_insert(outer);
+            }
+        }
+    }
+
+    /// Reads the arguments of a function-like macro. Returns None without consuming anything if
+    /// the next token isn't a '('.
+    fn macro_args(&mut self) -> Result<Option<Vec<Vec<Token>>>, String> {
+        let mut index = self.index;
+        if is_whitespace(self.tokens.get(index)) {
+            index += 1;
+        }
+        if !is_punctuation(self.tokens.get(index), "(") {
+            return Ok(None);
+        }
+        let start = index + 1;
+
+        // Find the ')' matching the '('
+        let mut depth = 0;
+        let end =
+            loop {
+                index += 1;
+                match self.tokens.get(index).map(|t| &t.kind) {
+                    Some(TokenKind::Newline) | None => return Err(
+                        "Expected a ')' to end the macro's arguments before the end of the line"
+                            .to_owned(),
+                    ),
+                    Some(TokenKind::Code(c)) if c == "(" => depth += 1,
+                    Some(TokenKind::Code(c)) if c == ")" && depth == 0 => break index,
+                    Some(TokenKind::Code(c)) if c == ")" => depth -= 1,
+                    _ => {}
+                }
+            };
+        self.index = end + 1;
+
+        Ok(Some(split_args(&self.tokens[start..end])))
+    }
+
+    /// Reads the arguments of a block macro, which are the rest of the line
+    fn line_args(&mut self) -> Vec<Vec<Token>> {
+        let (start, end) = self.directive_params();
+        split_args(&self.tokens[start..end])
+    }
+
     fn parse_directive(&mut self) -> Result<Option<()>, String> {
         // Get the type of directive, if it's a directive and exists
         let directive = match self.next() {
             Some(t)
                 if matches!(
                     t.kind,
-                    TokenKind::Include | TokenKind::Define | TokenKind::Undef
+                    TokenKind::Include
+                        | TokenKind::Define
+                        | TokenKind::Undef
+                        | TokenKind::Macro
+                        | TokenKind::Pragma
+                        | TokenKind::Error
+                        | TokenKind::Warning
+                        | TokenKind::Message
                 ) =>
             {
                 t.kind.to_owned()
@@ -117,45 +447,50 @@ impl<'a> Parser<'a> {
             None => return Err("parse_directive() called on EOF".to_owned()),
         };
 
-        self.consume_whitespace(); // Ignore whitespace if it's there
-
-        // Get arguments passed to the directive, up until a newline
-        let mut param_span = (self.index, self.index);
-        loop {
-            match self.peek() {
-                Some(t) if !matches!(t.kind, TokenKind::Newline) => {
-                    param_span.1 += 1;
-                    self.next();
-                }
-                _ => break,
-            };
-        }
+        let param_span = self.directive_params();
 
         match directive {
             TokenKind::Include => {
-                // If there's not exactly one parameter, error
-                if param_span.1 - param_span.0 == 0 {
-                    return Err(
-                        "#INCLUDE expects exactly one string parameter. No parameters found."
-                            .to_owned(),
-                    );
-                }
+                let (path, system) = include_path(&self.tokens[param_span.0..param_span.1])?;
 
                 // Get the file and insert it into the program
-                if let TokenKind::String(path) = &self.tokens[param_span.0].kind {
-                    let subprogram = read_file(path.as_str())?; // Read file
-                    let mut lexer = Lexer::new(path.as_str(), subprogram); // Lex the file
-                    lexer.tokenize()?;
-                    let mut parser = Parser::new(path.as_str(), lexer.tokens.as_slice()); // Parse the file
-                    parser.parse()?;
-                    self.output.push_str(&parser.output); // Add contents of the other file
-                    self.map.push(&parser.map); // Add the codemap of the other file
-                } else {
+                let path = find_include(&path, &self.filename, &self.env.include_dirs, system)?;
+                if !self.env.dependencies.contains(&path) {
+                    self.env.dependencies.push(path.to_owned());
+                }
+                let identity = file_identity(&path);
+                if self.env.once.contains(&identity) {
+                    return Ok(Some(())); // Already included, and marked as only needing it once
+                }
+                let subprogram = read_file(path.as_str())?; // Read file
+                let mut lexer = Lexer::new(path.as_str(), subprogram); // Lex the file
+                lexer.tokenize()?;
+
+                // A file wrapped in an include guard that's already defined would come out empty
+                if include_guard(&lexer.tokens).is_some_and(|g| self.env.deflist.contains_key(&g)) {
+                    return Ok(Some(()));
+                }
+                if let Some(start) = self.env.includes.iter().position(|(i, _)| *i == identity) {
+                    let chain: Vec<&str> = self.env.includes[start..]
+                        .iter()
+                        .map(|(_, name)| name.as_str())
+                        .chain([path.as_str()])
+                        .collect();
                     return Err(format!(
-                        "#INCLUDE expects just one string parameter. Found {:?}",
-                        self.tokens[self.index].kind
+                        "#INCLUDE of {} would include it inside itself, through:\n    {}\n  \
+                        Use `#pragma once` or an include guard in it so it's only included once",
+                        path,
+                        chain.join("\n    includes ")
                     ));
                 }
+
+                // Parse the file, with the same defines so they carry on into and out of it
+                let mut parser = self.child(path.as_str(), lexer.tokens.as_slice());
+                let result = parser.parse();
+                self.reclaim(&mut parser);
+                result?;
+                self.output.push_str(&parser.output); // Add contents of the other file
+                self.push_map(&parser.map); // Add the codemap of the other file
             }
             TokenKind::Define => {
                 if param_span.1 - param_span.0 == 0 {
@@ -167,15 +502,38 @@ impl<'a> Parser<'a> {
 
                 match &self.tokens[param_span.0].kind {
                     TokenKind::Code(def) => {
-                        if self.deflist.contains_key(def) {
-                            println!(
-                                "\x1b[95mBASM-PREPROCESSOR: \x1b[33mWarning on line {} of {}:\x1b[0m\n  #DEFINE is called on '{}', but it was previously defined (value was overwritten)", 
+                        if self.env.deflist.contains_key(def) {
+                            term::warning(
+                                &self.filename,
                                 self.line,
-                                self.filename,
-                                def
+                                &format!("#DEFINE is called on '{}', but it was previously defined (value was overwritten)", def),
                             );
                         }
-                        self.deflist.insert(def.to_owned(), param_span);
+
+                        // A '(' straight after the name starts a parameter list. With a space
+                        // before it, it's part of the replacement like anything else.
+                        let mut start = param_span.0 + 1;
+                        let params = if start < param_span.1 && is_punctuation(self.tokens.get(start), "(") {
+                            let (params, end) = parse_params("#DEFINE", &self.tokens[start + 1..param_span.1], true)?;
+                            start += end + 1;
+                            Some(params)
+                        } else {
+                            None
+                        };
+
+                        // Everything after the name (and the whitespace following it) is the replacement
+                        if start < param_span.1 && is_whitespace(self.tokens.get(start)) {
+                            start += 1;
+                        }
+                        let body = self.tokens[start.min(param_span.1)..param_span.1].to_vec();
+                        let definition = match params {
+                            Some(params) => Macro::Function { params, body },
+                            None => Macro::Constant(body),
+                        };
+                        self.env.deflist.insert(def.to_owned(), definition);
+                        self.env
+                            .defined_at
+                            .insert(def.to_owned(), (self.filename.to_owned(), self.line));
                     }
                     t => return Err(format!(
                         "#DEFINE expects a name as its first argument to be used as the constant's name.\n  Found {:?}",
@@ -183,6 +541,59 @@ impl<'a> Parser<'a> {
                     )),
                 };
             }
+            TokenKind::Macro => {
+                let (name, params) = match self.tokens[param_span.0..param_span.1].split_first() {
+                    Some((
+                        Token {
+                            kind: TokenKind::Code(name),
+                            ..
+                        },
+                        rest,
+                    )) if is_name(name) => (name.to_owned(), parse_params("#MACRO", rest, false)?.0),
+                    Some((t, _)) => {
+                        return Err(format!(
+                            "#MACRO expects a name as its first argument to be used as the macro's name.\n  Found {:?}",
+                            t.kind
+                        ))
+                    }
+                    None => {
+                        return Err(
+                            "#MACRO expects a name, followed by the names of its parameters. No parameters found."
+                                .to_owned(),
+                        )
+                    }
+                };
+
+                self.define_block(name, params)?;
+            }
+            TokenKind::Pragma => match &self.tokens[param_span.0..param_span.1] {
+                [Token {
+                    kind: TokenKind::Code(p),
+                    ..
+                }] if p.eq_ignore_ascii_case("once") => {
+                    self.env.once.insert(file_identity(&self.filename));
+                }
+                // Like in C, pragmas that aren't understood are ignored
+                params => term::warning(
+                    &self.filename,
+                    self.line,
+                    &format!(
+                        "Ignoring unknown #PRAGMA '{}'",
+                        params.iter().map(|t| t.to_string()).collect::<String>()
+                    ),
+                ),
+            },
+            TokenKind::Error => {
+                return Err(diagnostic_text(&self.tokens[param_span.0..param_span.1]));
+            }
+            TokenKind::Warning | TokenKind::Message => {
+                let text = diagnostic_text(&self.tokens[param_span.0..param_span.1]);
+                let (filename, line, text) = self.locate(&text);
+                match directive {
+                    TokenKind::Warning => term::warning(&filename, line, &text),
+                    _ => term::note(&filename, line, &text),
+                }
+            }
             TokenKind::Undef => {
                 // If there's not exactly one parameter, error
                 if param_span.1 - param_span.0 == 0 {
@@ -193,14 +604,23 @@ impl<'a> Parser<'a> {
                 }
 
                 match &self.tokens[param_span.0].kind {
-                    TokenKind::Code(def) => match self.deflist.remove(def) {
-                        Some(_) => {},
-                        None => println!("\x1b[95mBASM-PREPROCESSOR: \x1b[33mWarning on line {} of {}:\x1b[0m\n  #UNDEF is called on '{}', but it was not previously defined", self.line, self.filename, def),
+                    TokenKind::Code(def) => match self.env.deflist.remove(def) {
+                        Some(_) => {}
+                        None => term::warning(
+                            &self.filename,
+                            self.line,
+                            &format!(
+                                "#UNDEF is called on '{}', but it was not previously defined",
+                                def
+                            ),
+                        ),
+                    },
+                    t => {
+                        return Err(format!(
+                            "#UNDEF expects one name parameter\n  Found {:?}",
+                            t
+                        ))
                     }
-                    t => return Err(format!(
-                        "#UNDEF expects one name parameter\n  Found {:?}",
-                        t
-                    )),
                 };
             }
             t => {
@@ -214,13 +634,277 @@ impl<'a> Parser<'a> {
         Ok(Some(()))
     }
 
+    /// Handles the directives making up a conditional. Their branches are tracked even inside
+    /// branches that aren't taken, so the right #ENDIF ends each one.
+    fn parse_conditional(&mut self) -> Result<(), String> {
+        let directive = match self.next() {
+            Some(t) => t.kind.to_owned(),
+            None => return Err("parse_conditional() called on EOF".to_owned()),
+        };
+        let param_span = self.directive_params();
+        let params = &self.tokens[param_span.0..param_span.1];
+
+        match directive {
+            TokenKind::IfDef | TokenKind::IfNDef | TokenKind::If => {
+                // Nothing inside a branch that isn't taken is, so the condition isn't even checked
+                let active = self.active()
+                    && match directive {
+                        TokenKind::If => self.condition(params)?,
+                        TokenKind::IfDef => self.is_defined(&directive_name(&directive, params)?),
+                        _ => !self.is_defined(&directive_name(&directive, params)?),
+                    };
+                self.conditionals.push(Conditional {
+                    line: self.line,
+                    active,
+                    taken: active || !self.active(),
+                    seen_else: false,
+                });
+            }
+            TokenKind::Elif | TokenKind::Else => {
+                let current = match self.conditionals.last() {
+                    Some(c) if !c.seen_else => *c,
+                    Some(_) => {
+                        return Err(format!(
+                            "Found {} after the conditional's #ELSE",
+                            directive_spelling(&directive)
+                        ))
+                    }
+                    None => {
+                        return Err(format!(
+                            "Found {} without an #IF",
+                            directive_spelling(&directive)
+                        ))
+                    }
+                };
+
+                let active = match directive {
+                    TokenKind::Else if !params.is_empty() => {
+                        return Err("#ELSE doesn't take any parameters".to_owned())
+                    }
+                    TokenKind::Else => !current.taken,
+                    _ => !current.taken && self.condition(params)?,
+                };
+                *self.conditionals.last_mut().unwrap() = Conditional {
+                    active,
+                    taken: current.taken || active,
+                    seen_else: matches!(directive, TokenKind::Else),
+                    ..current
+                };
+            }
+            TokenKind::EndIf => {
+                if !params.is_empty() {
+                    return Err("#ENDIF doesn't take any parameters".to_owned());
+                }
+                if self.conditionals.pop().is_none() {
+                    return Err("Found an #ENDIF without an #IF".to_owned());
+                }
+            }
+            t => return Err(format!("parse_conditional() called on {:?}", t)),
+        }
+
+        Ok(())
+    }
+
+    /// Evaluates the expression of an #IF or #ELIF, which is true if it isn't 0
+    fn condition(&mut self, params: &[Token]) -> Result<bool, String> {
+        if params.is_empty() {
+            return Err("#IF and #ELIF expect an expression. No parameters found.".to_owned());
+        }
+
+        // `defined NAME` and `defined(NAME)` are replaced first, so NAME isn't replaced itself
+        let mut tokens = Vec::new();
+        let mut rest = params;
+        while let Some((tok, after)) = rest.split_first() {
+            rest = after;
+            if !matches!(&tok.kind, TokenKind::Code(c) if c == "defined") {
+                tokens.push(tok.clone());
+                continue;
+            }
+
+            let mut words = rest
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| !is_whitespace(Some(t)));
+            let (name, read) = match (words.next(), words.next(), words.next()) {
+                (Some((_, open)), Some((_, name)), Some((i, close)))
+                    if is_punctuation(Some(open), "(") && is_punctuation(Some(close), ")") =>
+                {
+                    (name, i + 1)
+                }
+                (Some((i, name)), _, _) => (name, i + 1),
+                _ => return Err("'defined' expects the name to check for".to_owned()),
+            };
+            let defined = match &name.kind {
+                TokenKind::Code(name) if is_name(name) => self.is_defined(name),
+                t => return Err(format!("'defined' expects a name, found {:?}", t)),
+            };
+
+            tokens.push(Token {
+                kind: TokenKind::Code((defined as u8).to_string()),
+                span: 1,
+            });
+            rest = &rest[read..];
+        }
+
+        let text = self.expand_to_string(&tokens)?;
+        match expr::evaluate(&text) {
+            Ok(value) => Ok(value != 0),
+            Err(e) => Err(format!("{} in '{}'", e, text.trim())),
+        }
+    }
+
+    /// Returns whether lines are being kept, which they are unless they're in a branch that isn't
+    /// taken
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    /// Reads the parameters of a directive, which go up to the end of the line, returning where
+    /// they start and end
+    fn directive_params(&mut self) -> (usize, usize) {
+        self.consume_whitespace(); // Ignore whitespace if it's there
+
+        let start = self.index;
+        while !matches!(
+            self.peek().map(|t| &t.kind),
+            Some(TokenKind::Newline) | None
+        ) {
+            self.index += 1;
+        }
+
+        (start, params_end(&self.tokens[..self.index], start))
+    }
+
+    /// Reads the lines up to the #ENDMACRO closing a #MACRO as the macro's body. They're written
+    /// out as empty lines, so the output still lines up with the source.
+    fn define_block(&mut self, name: String, params: Vec<String>) -> Result<(), String> {
+        let end = match self.tokens[self.index..]
+            .iter()
+            .position(|t| matches!(t.kind, TokenKind::Macro | TokenKind::EndMacro))
+        {
+            Some(i) if matches!(self.tokens[self.index + i].kind, TokenKind::EndMacro) => {
+                self.index + i
+            }
+            Some(_) => {
+                return Err(format!(
+                "#MACRO '{}' needs an #ENDMACRO before the next #MACRO, as they can't be nested",
+                name
+            ))
+            }
+            None => return Err(format!("#MACRO '{}' is missing its #ENDMACRO", name)),
+        };
+
+        // The body starts on the line after the #MACRO, and ends before the #ENDMACRO's line
+        let start = (self.index + 1).min(end);
+        let mut body_end = end;
+        while body_end > start
+            && matches!(
+                self.tokens[body_end - 1].kind,
+                TokenKind::Whitespace(_) | TokenKind::Newline
+            )
+        {
+            body_end -= 1;
+        }
+
+        if self.env.deflist.contains_key(&name) {
+            term::warning(
+                &self.filename,
+                self.line,
+                &format!("#MACRO is called on '{}', but it was previously defined (value was overwritten)", name),
+            );
+        }
+        let block = Block {
+            params,
+            body: self.tokens[start..body_end].to_vec(),
+            filename: self.filename.to_owned(),
+            line: self.line + 1,
+        };
+        self.env
+            .defined_at
+            .insert(name.to_owned(), (self.filename.to_owned(), self.line));
+        self.env.deflist.insert(name, Macro::Block(block));
+
+        // Skip to the end of the #ENDMACRO, keeping one line of output for each line skipped
+        while self.index <= end {
+            if matches!(self.tokens[self.index].kind, TokenKind::Newline) {
+                self.output.push('\n');
+                self.line += 1;
+                self.add_line_entry();
+            }
+            self.index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a code map entry for the line now being written
+    fn add_line_entry(&mut self) {
+        self.map.line_entries.push(LineEntry {
+            filename_index: 0,
+            line: self.line,
+            expansion: self.expansion,
+        });
+    }
+
+    /// Finds where the current line came from in the code map, adding where each macro that wrote
+    /// it was used to msg
+    fn locate(&self, msg: &str) -> (String, usize, String) {
+        let line = self.map.line_entries.len();
+        let (filename, source_line) = self.map.get_from(line);
+        let mut msg = msg.to_owned();
+        for expansion in self.map.expansions_of(line) {
+            msg.push_str(&format!(
+                "\n  In '{}', used at {}",
+                expansion.name,
+                self.map.expansion_location(expansion)
+            ));
+        }
+        (filename, source_line, msg)
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.env.deflist.contains_key(name) || builtins::is_builtin(name)
+    }
+
+    /// Returns the replacement for one of the names the preprocessor defines itself
+    fn builtin(&mut self, name: &str) -> Result<String, String> {
+        let replacement = match name {
+            "__FILE__" => string_literal(&self.location().0),
+            "__LINE__" => self.location().1.to_string(),
+            "__COUNTER__" => {
+                self.env.counter += 1;
+                (self.env.counter - 1).to_string()
+            }
+            "__DATE__" => builtins::date()?,
+            "__TIME__" => builtins::time()?,
+            _ => unreachable!("{} isn't a built in name", name),
+        };
+        Ok(replacement)
+    }
+
+    /// Returns the file and line __FILE__ and __LINE__ refer to. Like in C, inside a macro that's
+    /// where the macro was used, rather than where it was defined.
+    fn location(&self) -> (String, usize) {
+        match &self.call_site {
+            Some(call_site) => call_site.to_owned(),
+            None => (self.filename.to_owned(), self.line),
+        }
+    }
+
+    /// Returns the column of the next token, counting from 1
+    fn column(&self) -> usize {
+        self.tokens[..self.index]
+            .iter()
+            .rev()
+            .take_while(|t| !matches!(t.kind, TokenKind::Newline))
+            .map(|t| t.span)
+            .sum::<usize>()
+            + 1
+    }
+
     fn consume_whitespace(&mut self) {
         // If whitespace is found, skip over it
-        if let Some(Token {
-            kind: TokenKind::Whitespace,
-            ..
-        }) = self.peek()
-        {
+        if is_whitespace(self.peek()) {
             self.index += 1;
         }
     }
@@ -243,3 +927,812 @@ impl<'a> Parser<'a> {
         }
     }
 }
+
+/// Gets the file an #INCLUDE names, and whether it was a system include written in <>
+fn include_path(params: &[Token]) -> Result<(String, bool), String> {
+    match params {
+        [] => Err("#INCLUDE expects exactly one string parameter. No parameters found.".to_owned()),
+        [Token {
+            kind: TokenKind::String(path),
+            ..
+        }] if path.is_empty() => Err("#INCLUDE needs the name of a file, not \"\"".to_owned()),
+        [Token {
+            kind: TokenKind::String(path),
+            ..
+        }] => Ok((path.to_owned(), false)),
+        [open, path @ .., close]
+            if is_punctuation(Some(open), "<")
+                && is_punctuation(Some(close), ">")
+                && !path.is_empty() =>
+        {
+            Ok((path.iter().map(|t| t.to_string()).collect(), true))
+        }
+        [t, ..] => Err(format!(
+            "#INCLUDE expects just one string parameter, or a path in <>. Found {:?}",
+            t.kind
+        )),
+    }
+}
+
+/// Returns the name guarding a file whose tokens are all inside `#IFNDEF NAME` ... `#ENDIF`, which
+/// is how files make sure they're only included once
+fn include_guard(tokens: &[Token]) -> Option<String> {
+    let mut tokens = tokens.iter().filter(|t| {
+        !matches!(
+            t.kind,
+            TokenKind::Whitespace(_) | TokenKind::Comment(_) | TokenKind::Newline
+        )
+    });
+
+    if !matches!(tokens.next()?.kind, TokenKind::IfNDef) {
+        return None;
+    }
+    let name = match &tokens.next()?.kind {
+        TokenKind::Code(name) if is_name(name) => name.to_owned(),
+        _ => return None,
+    };
+
+    // The #IFNDEF's own #ENDIF has to be the last token, with no other branches before it
+    let mut depth = 0;
+    loop {
+        match tokens.next()?.kind {
+            TokenKind::IfDef | TokenKind::IfNDef | TokenKind::If => depth += 1,
+            TokenKind::Elif | TokenKind::Else if depth == 0 => return None,
+            TokenKind::EndIf if depth == 0 => break,
+            TokenKind::EndIf => depth -= 1,
+            _ => {}
+        }
+    }
+    match tokens.next() {
+        None => Some(name),
+        Some(_) => None,
+    }
+}
+
+/// Reads a macro's parameter names, separated by commas. If closed, they end at a ')', and how
+/// many tokens were read including it is returned. Otherwise they go on to the end of tokens.
+fn parse_params(
+    directive: &str,
+    tokens: &[Token],
+    closed: bool,
+) -> Result<(Vec<String>, usize), String> {
+    let mut params: Vec<String> = Vec::new();
+    let mut expect_name = true;
+
+    for (i, tok) in tokens.iter().enumerate() {
+        match &tok.kind {
+            TokenKind::Whitespace(_) => {}
+            TokenKind::Code(c) if closed && c == ")" && (!expect_name || params.is_empty()) => {
+                return Ok((params, i + 1))
+            }
+            TokenKind::Code(c) if c == "," && !expect_name => expect_name = true,
+            TokenKind::Code(name) if expect_name && is_name(name) => {
+                if params.contains(name) {
+                    return Err(format!(
+                        "{} has more than one parameter called '{}'",
+                        directive, name
+                    ));
+                }
+                params.push(name.to_owned());
+                expect_name = false;
+            }
+            t => {
+                return Err(format!(
+                    "Unexpected {:?} in {}'s parameter list",
+                    t, directive
+                ))
+            }
+        }
+    }
+
+    match (closed, expect_name && !params.is_empty()) {
+        (true, _) => Err(format!("{}'s parameter list is missing a ')'", directive)),
+        (false, true) => Err(format!("{}'s parameter list ends with a ','", directive)),
+        (false, false) => Ok((params, tokens.len())),
+    }
+}
+
+/// Splits a macro's arguments at commas that aren't inside brackets
+fn split_args(tokens: &[Token]) -> Vec<Vec<Token>> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+
+    for tok in tokens {
+        match &tok.kind {
+            TokenKind::Code(c) if c == "," && depth == 0 => {
+                args.push(Vec::new());
+                continue;
+            }
+            TokenKind::Code(c) if c == "(" => depth += 1,
+            TokenKind::Code(c) if c == ")" => depth -= 1,
+            _ => {}
+        }
+        args.last_mut().unwrap().push(tok.clone());
+    }
+
+    for arg in &mut args {
+        trim_whitespace(arg);
+    }
+    // `NAME()` passes no arguments rather than one empty one
+    if args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+
+    args
+}
+
+/// Renames the labels defined in a block macro's body, along with every use of them in it, so
+/// each expansion has its own. Parameters aren't renamed, so labels passed in stay as they are.
+fn localize_labels(body: &[Token], params: &[String], expansion: usize) -> Vec<Token> {
+    // A name pasted onto something else with `##` is only part of the label
+    let pasted = |i: usize| {
+        let before = body[..i].iter().rev().find(|t| !is_whitespace(Some(t)));
+        is_punctuation(before, "##")
+    };
+    let labels: Vec<&String> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(i, tok)| match &tok.kind {
+            TokenKind::Code(name)
+                if is_punctuation(body.get(i + 1), ":")
+                    && is_name(name)
+                    && !params.contains(name)
+                    && !pasted(i) =>
+            {
+                Some(name)
+            }
+            _ => None,
+        })
+        .collect();
+
+    body.iter()
+        .map(|tok| match &tok.kind {
+            TokenKind::Code(name) if labels.contains(&name) => Token {
+                kind: TokenKind::Code(format!("{}__{}", name, expansion)),
+                span: tok.span,
+            },
+            _ => tok.clone(),
+        })
+        .collect()
+}
+
+/// Replaces each parameter in a function-like macro's body with the argument passed for it, or
+/// with a string of it after a '#'. Then pastes together tokens joined with `##`.
+fn substitute(
+    name: &str,
+    params: &[String],
+    args: &[Vec<Token>],
+    body: &[Token],
+) -> Result<Vec<Token>, String> {
+    if params.len() != args.len() {
+        return Err(format!(
+            "'{}' takes {} argument(s), but was given {}",
+            name,
+            params.len(),
+            args.len()
+        ));
+    }
+
+    let param_index = |tok: Option<&Token>| match tok.map(|t| &t.kind) {
+        Some(TokenKind::Code(c)) => params.iter().position(|p| p == c),
+        _ => None,
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while let Some(tok) = body.get(i) {
+        i += 1;
+        if is_punctuation(Some(tok), "#") {
+            // `#param` turns the argument into a string
+            if is_whitespace(body.get(i)) {
+                i += 1;
+            }
+            let arg = match param_index(body.get(i)) {
+                Some(p) => &args[p],
+                None => {
+                    return Err(format!(
+                        "'#' in '{}' has to be followed by a parameter",
+                        name
+                    ))
+                }
+            };
+            i += 1;
+            tokens.push(Token {
+                kind: TokenKind::String(stringify(arg)),
+                span: tok.span,
+            });
+            continue;
+        }
+
+        match param_index(Some(tok)) {
+            Some(p) => tokens.extend_from_slice(&args[p]),
+            None => tokens.push(tok.clone()),
+        }
+    }
+
+    paste(name, &tokens)
+}
+
+/// Joins the tokens either side of each `##` into one
+fn paste(name: &str, tokens: &[Token]) -> Result<Vec<Token>, String> {
+    let mut pasted: Vec<Token> = Vec::new();
+    let mut tokens = tokens.iter().peekable();
+    while let Some(tok) = tokens.next() {
+        if !is_punctuation(Some(tok), "##") {
+            pasted.push(tok.clone());
+            continue;
+        }
+
+        // Whitespace around the `##` goes, as it's not part of either side
+        while is_whitespace(pasted.last()) {
+            pasted.pop();
+        }
+        if is_whitespace(tokens.peek().copied()) {
+            tokens.next();
+        }
+
+        let (left, right) = match (pasted.pop(), tokens.next()) {
+            (Some(left), Some(right))
+                if !matches!(left.kind, TokenKind::Newline)
+                    && !matches!(right.kind, TokenKind::Newline) =>
+            {
+                (left, right)
+            }
+            _ => {
+                return Err(format!(
+                    "'##' in '{}' needs something to paste on both sides of it",
+                    name
+                ))
+            }
+        };
+        let kind = match (&left.kind, &right.kind) {
+            (
+                TokenKind::Code(l) | TokenKind::NoExpand(l),
+                TokenKind::Code(r) | TokenKind::NoExpand(r),
+            ) => TokenKind::Code(format!("{l}{r}")),
+            // Pasting onto a string adds to the text inside its quotes
+            (
+                TokenKind::String(l) | TokenKind::Code(l),
+                TokenKind::String(r) | TokenKind::Code(r),
+            ) => TokenKind::String(format!("{l}{r}")),
+            _ => {
+                return Err(format!(
+                    "'##' in '{}' can't paste '{}' and '{}' together",
+                    name, left, right
+                ))
+            }
+        };
+        pasted.push(Token {
+            kind,
+            span: left.span + right.span,
+        });
+    }
+
+    Ok(pasted)
+}
+
+/// Stops the hidden names in a macro's replacement from being replaced again, apart from its
+/// parameters, which are replaced with arguments that can use any macro
+fn hide(body: &[Token], params: &[String], hidden: &[String]) -> Vec<Token> {
+    body.iter()
+        .map(|tok| match &tok.kind {
+            TokenKind::Code(name) if hidden.contains(name) && !params.contains(name) => Token {
+                kind: TokenKind::NoExpand(name.to_owned()),
+                span: tok.span,
+            },
+            _ => tok.clone(),
+        })
+        .collect()
+}
+
+/// Lists macros that were used inside each other, with repeats shown once like `R (x99)`. Only
+/// the innermost few are listed, as that's where the macros keep going.
+fn describe_chain(names: &[String]) -> String {
+    let mut runs: Vec<(&str, usize)> = Vec::new();
+    for name in names {
+        match runs.last_mut() {
+            Some((last, count)) if last == name => *count += 1,
+            _ => runs.push((name, 1)),
+        }
+    }
+
+    let skipped = runs.len().saturating_sub(8);
+    let runs: Vec<String> = (skipped > 0)
+        .then(|| "...".to_owned())
+        .into_iter()
+        .chain(runs[skipped..].iter().map(|(name, count)| match count {
+            1 => name.to_string(),
+            _ => format!("{} (x{})", name, count),
+        }))
+        .collect();
+    runs.join(" -> ")
+}
+
+/// Returns the text of a macro argument, for `#param`. Like in C, each run of whitespace in it
+/// becomes a single space.
+fn stringify(arg: &[Token]) -> String {
+    let text: String = arg
+        .iter()
+        .map(|t| match t.kind {
+            TokenKind::Whitespace(_) => " ".to_owned(),
+            _ => t.to_string(),
+        })
+        .collect();
+    text.trim().to_owned()
+}
+
+fn trim_whitespace(tokens: &mut Vec<Token>) {
+    while is_whitespace(tokens.last()) {
+        tokens.pop();
+    }
+    let leading = tokens.iter().take_while(|t| is_whitespace(Some(t))).count();
+    tokens.drain(..leading);
+}
+
+fn is_whitespace(tok: Option<&Token>) -> bool {
+    matches!(
+        tok,
+        Some(Token {
+            kind: TokenKind::Whitespace(_),
+            ..
+        })
+    )
+}
+
+/// Returns where the parameters on a line from start up to tokens.len() end, as the whitespace and
+/// comment at the end of it aren't part of them
+fn params_end(tokens: &[Token], start: usize) -> usize {
+    let mut end = tokens.len();
+    while end > start
+        && matches!(
+            tokens[end - 1].kind,
+            TokenKind::Whitespace(_) | TokenKind::Comment(_)
+        )
+    {
+        end -= 1;
+    }
+    end
+}
+
+fn is_punctuation(tok: Option<&Token>, punctuation: &str) -> bool {
+    matches!(tok, Some(Token { kind: TokenKind::Code(c), .. }) if c == punctuation)
+}
+
+/// Gets the text of an #ERROR, #WARNING or #MESSAGE, which is the rest of the line as written but
+/// with strings unquoted
+fn diagnostic_text(params: &[Token]) -> String {
+    params
+        .iter()
+        .map(|t| match &t.kind {
+            TokenKind::String(s) => s.to_owned(),
+            _ => t.to_string(),
+        })
+        .collect()
+}
+
+/// Gets the name an #IFDEF or #IFNDEF checks for
+fn directive_name(directive: &TokenKind, params: &[Token]) -> Result<String, String> {
+    match params {
+        [Token {
+            kind: TokenKind::Code(name),
+            ..
+        }] if is_name(name) => Ok(name.to_owned()),
+        _ => Err(format!(
+            "{} expects exactly one name parameter",
+            directive_spelling(directive)
+        )),
+    }
+}
+
+fn directive_spelling(directive: &TokenKind) -> String {
+    Token {
+        kind: directive.to_owned(),
+        span: 0,
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{preprocess, Options};
+    use basm_codemap::Location;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Preprocesses source, returning its output without the lines left empty by directives
+    fn run(source: &str) -> Result<Vec<String>, String> {
+        run_with(source, &Options::default())
+    }
+
+    fn run_with(source: &str, options: &Options) -> Result<Vec<String>, String> {
+        let (output, _) = preprocess(source, options)?;
+        Ok(output
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.to_owned())
+            .collect())
+    }
+
+    /// Writes files into a directory of their own, returning where it is
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("basm-preprocessor-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (filename, contents) in files {
+            fs::write(dir.join(filename), contents).unwrap();
+        }
+        dir
+    }
+
+    fn run_file(dir: &Path, filename: &str) -> Result<Vec<String>, String> {
+        let path = dir.join(filename).to_string_lossy().into_owned();
+        let options = Options {
+            filename: path.clone(),
+            ..Options::default()
+        };
+        run_with(&read_file(&path)?, &options)
+    }
+
+    #[test]
+    fn splits_arguments_at_top_level_commas() {
+        let mut lexer = Lexer::new("test.basm", "(1, 2), g(3, (4)),  x ".to_owned());
+        lexer.tokenize().unwrap();
+        let args: Vec<String> = split_args(&lexer.tokens)
+            .iter()
+            .map(|arg| stringify(arg))
+            .collect();
+        assert_eq!(args, ["(1, 2)", "g(3, (4))", "x"]);
+    }
+
+    #[test]
+    fn passes_nested_parentheses_as_one_argument() {
+        let output = run("#define F(a, b) a + b\nF((1, 2), g(3, (4)))\n").unwrap();
+        assert_eq!(output, ["(1, 2) + g(3, (4))"]);
+    }
+
+    #[test]
+    fn no_arguments_is_not_one_empty_argument() {
+        assert_eq!(run("#define F() x\nF()\n").unwrap(), ["x"]);
+        assert!(run("#define F(a) a\nF(1, 2)\n").is_err());
+    }
+
+    #[test]
+    fn skips_the_right_of_and_and_or_once_decided() {
+        let source = "#if defined(X) && 10 / X > 1\nyes\n#else\nno\n#endif\n";
+        assert_eq!(run(source).unwrap(), ["no"]);
+        assert_eq!(run(&format!("#define X 2\n{}", source)).unwrap(), ["yes"]);
+        assert_eq!(run("#if 1 || 1 % 0\nyes\n#endif\n").unwrap(), ["yes"]);
+        assert!(run("#if 1 && 1 / 0\nyes\n#endif\n").is_err());
+        assert!(run("#if 0 && (1\nyes\n#endif\n").is_err());
+    }
+
+    #[test]
+    fn strings_can_be_empty() {
+        assert_eq!(
+            run("#define E \"\"\n.db E, \"\"\n").unwrap(),
+            [".db \"\", \"\""]
+        );
+        let error = run("#include \"\"\n").unwrap_err();
+        assert!(error.contains("needs the name of a file"), "{}", error);
+    }
+
+    #[test]
+    fn counter_counts_up_each_time_it_is_used() {
+        let output = run("#define NEXT __COUNTER__\n__COUNTER__ NEXT\nNEXT\n").unwrap();
+        assert_eq!(output, ["0 1", "2"]);
+    }
+
+    #[test]
+    fn file_and_line_are_where_the_name_is_used() {
+        let dir = write_files(
+            "file-line",
+            &[
+                ("inc.basm", "\n__FILE__ __LINE__\n"),
+                ("main.basm", "#include \"inc.basm\"\n__FILE__ __LINE__\n"),
+            ],
+        );
+        let output = run_file(&dir, "main.basm").unwrap();
+        let file = |name: &str| string_literal(&dir.join(name).to_string_lossy());
+        assert_eq!(
+            output,
+            [
+                format!("{} 2", file("inc.basm")),
+                format!("{} 2", file("main.basm"))
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_macro_lines_map_to_the_body_and_the_use() {
+        let source =
+            "#macro TWICE r\nloop: add r, 1\njmp loop\n#endmacro\nx:  TWICE ac\n  TWICE br\n";
+        let (output, map) = preprocess(source, &Options::default()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[4..],
+            [
+                "x:  loop__0: add ac, 1",
+                "jmp loop__0",
+                "  loop__1: add br, 1",
+                "jmp loop__1"
+            ]
+        );
+
+        // Each line is where it's written in the body, as part of the use that wrote it
+        let entries: Vec<(usize, Option<usize>)> = map.line_entries[4..8]
+            .iter()
+            .map(|e| (e.line, e.expansion))
+            .collect();
+        assert_eq!(
+            entries,
+            [(2, Some(0)), (3, Some(0)), (2, Some(1)), (3, Some(1))]
+        );
+        let uses: Vec<Location> = map
+            .expansions
+            .iter()
+            .map(|e| map.expansion_location(e))
+            .collect();
+        let used_at = |line, column| Location {
+            filename: "stdin",
+            line,
+            column: Some(column),
+        };
+        assert_eq!(uses, [used_at(5, 5), used_at(6, 3)]);
+    }
+
+    #[test]
+    fn nested_block_macros_lead_back_to_the_outer_use() {
+        let source = "#macro INNER\ninner\n#endmacro\n#macro OUTER\nINNER\n#endmacro\nOUTER\n";
+        let (output, map) = preprocess(source, &Options::default()).unwrap();
+        assert_eq!(output.lines().nth(6), Some("inner"));
+        let chain: Vec<(&str, usize)> = map
+            .expansions_of(7)
+            .iter()
+            .map(|e| (e.name.as_str(), e.line))
+            .collect();
+        assert_eq!(chain, [("INNER", 5), ("OUTER", 7)]);
+
+        let error = run("#macro BAD\nok\n#error \"bad\"\n#endmacro\nBAD\n").unwrap_err();
+        assert!(
+            error.contains("In expansion of 'BAD', line 3 of stdin"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn defines_are_shared_with_included_files() {
+        let dir = write_files(
+            "shared-defines",
+            &[
+                (
+                    "inc.basm",
+                    "#define FROM_INC FROM_MAIN + 1\ninc FROM_MAIN\nWHERE\n",
+                ),
+                (
+                    "main.basm",
+                    "#define FROM_MAIN 1\n#define WHERE __FILE__\n#include \"inc.basm\"\nmain FROM_INC\nWHERE\n",
+                ),
+            ],
+        );
+        let file = |name: &str| string_literal(&dir.join(name).to_string_lossy());
+        assert_eq!(
+            run_file(&dir, "main.basm").unwrap(),
+            [
+                "inc 1".to_owned(),
+                file("inc.basm"),
+                "main 1 + 1".to_owned(),
+                file("main.basm")
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn command_line_defines_are_defined_before_the_source() {
+        let define = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+        let options = Options {
+            defines: vec![
+                define("BOARD", "rev2"),
+                define("DEBUG", "1"),
+                define("SUM", "1 + 2"),
+            ],
+            ..Options::default()
+        };
+        let source = "BOARD\n#ifdef DEBUG\ndebug\n#endif\n#if SUM == 3\nsum\n#endif\n";
+        assert_eq!(
+            run_with(source, &options).unwrap(),
+            ["rev2", "debug", "sum"]
+        );
+
+        // -U wins over -D for the same name
+        let options = Options {
+            undefines: vec!["DEBUG".to_owned()],
+            ..options
+        };
+        assert_eq!(run_with(source, &options).unwrap(), ["rev2", "sum"]);
+    }
+
+    #[test]
+    fn errors_stop_at_the_line_they_are_on() {
+        let dir = write_files(
+            "error",
+            &[
+                ("inc.basm", "ok\n#error \"unsupported board\"\n"),
+                ("main.basm", "\n\n#include \"inc.basm\"\nnever\n"),
+            ],
+        );
+        let error = run_file(&dir, "main.basm").unwrap_err();
+        let at = |line: usize, name: &str| {
+            format!("Error on line {} of {}", line, dir.join(name).display())
+        };
+        assert!(error.contains(&at(3, "main.basm")), "{}", error);
+        assert!(error.contains(&at(2, "inc.basm")), "{}", error);
+        assert!(error.ends_with("unsupported board"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn warnings_carry_on_and_are_located_through_macros() {
+        assert_eq!(
+            run("#warning \"careful\"\n#message \"hello\"\nafter\n").unwrap(),
+            ["after"]
+        );
+
+        // #warning and #message are located the same way, from the line they're on
+        let mut lexer = Lexer::new("main.basm", "#macro M\nok\n#endmacro\nM".to_owned());
+        lexer.tokenize().unwrap();
+        let mut parser = Parser::new("main.basm", &lexer.tokens);
+        parser.parse().unwrap();
+        assert_eq!(
+            parser.locate("careful"),
+            (
+                "main.basm".to_owned(),
+                2,
+                "careful\n  In 'M', used at main.basm:4:1".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn traces_end_lines_with_what_was_expanded_on_them() {
+        let options = Options {
+            trace_expansions: true,
+            defines: vec![("D".to_owned(), "0".to_owned())],
+            ..Options::default()
+        };
+        let source = "#define A 1\n#define B A + 1\nB\n#macro M\nm A\n#endmacro\nM\nplain\nD";
+        assert_eq!(
+            run_with(source, &options).unwrap(),
+            [
+                "1 + 1 ; expanded B from stdin:2, A from stdin:1 in B",
+                "m 1 ; expanded M from stdin:4, A from stdin:1 in M",
+                "plain",
+                "0 ; expanded D from <command line>:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn takes_the_first_branch_that_holds() {
+        let source = "\
+#if X == 1
+one
+#elif X == 2
+#if defined(Y)
+two and y
+#elif Y == 0
+two without y
+#else
+unreachable
+#endif
+#else
+other
+#endif
+";
+        let with = |defines: &str| run(&format!("{}{}", defines, source)).unwrap();
+        assert_eq!(with("#define X 1\n"), ["one"]);
+        assert_eq!(with("#define X 2\n#define Y\n"), ["two and y"]);
+        assert_eq!(with("#define X 2\n"), ["two without y"]);
+        assert_eq!(with(""), ["other"]);
+    }
+
+    #[test]
+    fn rejects_unbalanced_conditionals() {
+        assert!(run("#if 1\nx\n").is_err());
+        assert!(run("#else\n").is_err());
+        assert!(run("#if 1\n#else\n#elif 1\n#endif\n").is_err());
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("a.basm", "#include \"b.basm\"\na\n"),
+                ("b.basm", "#include \"a.basm\"\nb\n"),
+            ],
+        );
+        let error = run_file(&dir, "a.basm").unwrap_err();
+        assert!(
+            error.contains("would include it inside itself"),
+            "{}",
+            error
+        );
+        // The chain goes from the outermost file through b.basm and back to a.basm
+        let chain = &error[error.find("through:").unwrap()..];
+        let b_at = chain.find("includes ").unwrap();
+        assert!(chain[..b_at].contains("a.basm"), "{}", error);
+        assert!(chain[b_at..].starts_with("includes ") && chain[b_at..].contains("b.basm"));
+        assert!(chain.rfind("a.basm").unwrap() > chain.find("b.basm").unwrap());
+        assert!(error.contains("#pragma once"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn includes_pragma_once_files_once() {
+        let dir = write_files(
+            "once",
+            &[
+                ("once.basm", "#pragma once\nonce\n"),
+                (
+                    "main.basm",
+                    "#include \"once.basm\"\n#include \"once.basm\"\nmain\n",
+                ),
+            ],
+        );
+        assert_eq!(run_file(&dir, "main.basm").unwrap(), ["once", "main"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_files_whose_include_guard_is_defined() {
+        let guarded = "#ifndef GUARD\n#define GUARD\nguarded\n#endif\n";
+        let dir = write_files(
+            "guard",
+            &[
+                ("guarded.basm", guarded),
+                (
+                    "main.basm",
+                    "#include \"guarded.basm\"\n#include \"guarded.basm\"\nmain\n",
+                ),
+            ],
+        );
+        assert_eq!(run_file(&dir, "main.basm").unwrap(), ["guarded", "main"]);
+        fs::remove_dir_all(dir).unwrap();
+
+        // Skipped before it's checked for cycles, so a guarded file can even include itself
+        let own = "#ifndef OWN\n#define OWN\n#include \"own.basm\"\nown\n#endif\n";
+        let dir = write_files("own-guard", &[("own.basm", own)]);
+        assert_eq!(run_file(&dir, "own.basm").unwrap(), ["own"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pastes_and_stringifies_arguments() {
+        let output = run("#define ENTRY(n) n ## _entry: db #n\nENTRY(start)\n").unwrap();
+        assert_eq!(output, ["start_entry: db \"start\""]);
+        assert_eq!(
+            run("#define S(a) #a\nS(  x   +  y )\n").unwrap(),
+            ["\"x + y\""]
+        );
+    }
+
+    #[test]
+    fn defines_are_not_replaced_inside_themselves() {
+        assert_eq!(run("#define A A\nA\n").unwrap(), ["A"]);
+        assert_eq!(run("#define A B\n#define B A\nA B\n").unwrap(), ["A B"]);
+        assert_eq!(
+            run("#define A 1 + B\n#define B 2 * A\nA\n").unwrap(),
+            ["1 + 2 * A"]
+        );
+    }
+
+    #[test]
+    fn recursive_block_macros_stop_at_the_depth_limit() {
+        let error = run("#macro LOOP\nLOOP\n#endmacro\nLOOP\n").unwrap_err();
+        assert!(error.contains("100"), "{}", error);
+    }
+}
@@ -0,0 +1,44 @@
+use basm_cli::term::{verbosity_at_least, GRAY, MAGENTA, YELLOW};
+
+pub use basm_cli::term::{paint, set_color, set_verbosity, ColorChoice, Verbosity};
+
+const PREFIX: &str = "BASM-PREPROCESSOR:";
+
+/// Formats the header used for errors at a particular location in a source file
+pub fn error_at(filename: &str, line: usize) -> String {
+    basm_cli::term::error_at(Some(filename), line)
+}
+
+pub fn error(msg: &str) {
+    basm_cli::term::error(PREFIX, msg);
+}
+
+pub fn warning(filename: &str, line: usize, msg: &str) {
+    if verbosity_at_least(Verbosity::Normal) {
+        eprintln!(
+            "{} {}\n  {}",
+            paint(MAGENTA, PREFIX),
+            paint(
+                YELLOW,
+                &format!("Warning on line {} of {}:", line, filename)
+            ),
+            msg
+        );
+    }
+}
+
+/// Prints a message written in the source, like with #MESSAGE
+pub fn note(filename: &str, line: usize, msg: &str) {
+    if verbosity_at_least(Verbosity::Normal) {
+        eprintln!(
+            "{} {}\n  {}",
+            paint(MAGENTA, PREFIX),
+            paint(GRAY, &format!("Message on line {} of {}:", line, filename)),
+            msg
+        );
+    }
+}
+
+pub fn info(msg: &str) {
+    basm_cli::term::info(PREFIX, msg);
+}
@@ -0,0 +1,248 @@
+use crate::image::Image;
+use crate::lexer::TokenKind;
+use crate::parser::{Expr, ExprKind};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub type Symbols = BTreeMap<String, u16>;
+
+/// Checks parsed statements, once macros have been expanded and .if has decided which of them
+/// are assembled. The first pass works out every label and constant, and the second checks every
+/// name used is defined. Then every .assert is checked, and all the ones that fail are returned
+/// together.
+/// Nothing is encoded yet, so labels don't have addresses.
+pub fn assemble(ast: &[Expr]) -> Result<Image, Vec<(String, usize)>> {
+    let (labels, constants) = layout(ast).map_err(|e| vec![e])?;
+    let statements = ast.to_vec();
+
+    for statement in &statements {
+        if matches!(
+            statement.kind,
+            ExprKind::Instruction(_) | ExprKind::Directive(TokenKind::Db)
+        ) {
+            check_names(statement, &labels, &constants).map_err(|e| vec![(e, statement.line)])?;
+        }
+    }
+
+    // Assertions wait until everything else is known, so they can use constants defined after them
+    let failures: Vec<(String, usize)> = statements
+        .iter()
+        .filter(|statement| matches!(statement.kind, ExprKind::Directive(TokenKind::Assert)))
+        .filter_map(|statement| check_assertion(statement, &labels, &constants).err())
+        .collect();
+    if !failures.is_empty() {
+        return Err(failures);
+    }
+
+    Ok(Image {
+        statements,
+        labels,
+        constants,
+    })
+}
+
+/// Finds every label and constant. Constants are worked out in order, so they can only use
+/// constants defined before them.
+fn layout(ast: &[Expr]) -> Result<(BTreeSet<String>, Symbols), (String, usize)> {
+    let mut labels = BTreeSet::new();
+    let mut constants = Symbols::new();
+
+    for statement in ast {
+        let line = statement.line;
+        match &statement.kind {
+            ExprKind::Label(name) if labels.contains(name) || constants.contains_key(name) => {
+                return Err((format!("Label '{}' is defined more than once", name), line));
+            }
+            ExprKind::Label(name) => {
+                labels.insert(name.to_owned());
+            }
+            ExprKind::Directive(TokenKind::Org) => {
+                org_address(statement, &labels, &constants)?;
+            }
+            ExprKind::Directive(TokenKind::Equ) => {
+                let (name, value) = constant(statement, &labels, &constants)?;
+                if labels.contains(name) || constants.insert(name.to_owned(), value).is_some() {
+                    return Err((format!("'{}' is defined more than once", name), line));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok((labels, constants))
+}
+
+/// Reads an .equ's name and works out its value
+pub fn constant<'a>(
+    statement: &'a Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<(&'a str, u16), (String, usize)> {
+    let (name, value) = match statement.exprs.as_slice() {
+        [name, value] if matches!(value.kind, ExprKind::Expression) => match name.lone_label() {
+            Some(name) => (name, value),
+            None => {
+                return Err((
+                    ".equ expects a name for the constant first".to_owned(),
+                    statement.line,
+                ))
+            }
+        },
+        _ => {
+            return Err((
+                ".equ expects a name and a value, like `.equ SIZE, 16`".to_owned(),
+                statement.line,
+            ))
+        }
+    };
+
+    match constant_value(value, labels, constants) {
+        Ok(value) => Ok((name, value)),
+        Err(e) => Err((
+            format!(".equ needs a value that's known where it's used. {}", e),
+            statement.line,
+        )),
+    }
+}
+
+/// Errors if an .assert's condition doesn't hold, with its message or else the condition itself
+fn check_assertion(
+    statement: &Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<(), (String, usize)> {
+    let (expr, message) = match statement.exprs.as_slice() {
+        [expr] if matches!(expr.kind, ExprKind::Expression) => (expr, expr.to_source()),
+        [expr, Expr {
+            kind: ExprKind::String(message),
+            ..
+        }] if matches!(expr.kind, ExprKind::Expression) => (expr, message.to_owned()),
+        _ => {
+            return Err((
+                ".assert expects a condition, optionally followed by a message".to_owned(),
+                statement.line,
+            ))
+        }
+    };
+
+    match constant_value(expr, labels, constants) {
+        Ok(0) => Err((format!("Assertion failed: {}", message), statement.line)),
+        Ok(_) => Ok(()),
+        Err(e) => Err((format!(".assert can't be checked. {}", e), statement.line)),
+    }
+}
+
+/// Evaluates the address given to .org, which can only use constants defined before it
+fn org_address(
+    statement: &Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<u16, (String, usize)> {
+    match statement.exprs.as_slice() {
+        [expr] if matches!(expr.kind, ExprKind::Expression) => {
+            constant_value(expr, labels, constants).map_err(|e| {
+                (
+                    format!(".org needs an address that's known where it's used. {}", e),
+                    statement.line,
+                )
+            })
+        }
+        _ => Err((
+            ".org expects exactly one address".to_owned(),
+            statement.line,
+        )),
+    }
+}
+
+/// Evaluates an expression that has to be known while assembling, which labels can't be part of
+pub fn constant_value(
+    expr: &Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<u16, String> {
+    if let Some(label) = names(expr).into_iter().find(|name| labels.contains(*name)) {
+        return Err(format!(
+            "'{}' is a label, and labels have no address until instructions are encoded",
+            label
+        ));
+    }
+
+    evaluate(expr, constants)
+}
+
+/// Errors if a statement uses a name that isn't a label or constant
+fn check_names(
+    statement: &Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<(), String> {
+    match names(statement)
+        .into_iter()
+        .find(|name| !labels.contains(*name) && !constants.contains_key(*name))
+    {
+        Some(name) => Err(format!("Unknown label '{}'", name)),
+        None => Ok(()),
+    }
+}
+
+/// Returns every label or constant used in expr
+fn names(expr: &Expr) -> Vec<&str> {
+    match &expr.kind {
+        ExprKind::Label(name) => vec![name],
+        _ => expr.exprs.iter().flat_map(names).collect(),
+    }
+}
+
+/// Evaluates an expression with 16-bit wrapping arithmetic. Comparisons are unsigned and give 1
+/// if they hold and 0 if they don't.
+pub fn evaluate(expr: &Expr, symbols: &Symbols) -> Result<u16, String> {
+    match &expr.kind {
+        ExprKind::Expression if expr.exprs.len() > 1 => {
+            let mut value = evaluate(&expr.exprs[0], symbols)?;
+            for pair in expr.exprs[1..].chunks(2) {
+                let rhs = evaluate(&pair[1], symbols)?;
+                let holds = match &pair[0].kind {
+                    ExprKind::Operator(TokenKind::Equal) => value == rhs,
+                    ExprKind::Operator(TokenKind::NotEqual) => value != rhs,
+                    ExprKind::Operator(TokenKind::Less) => value < rhs,
+                    ExprKind::Operator(TokenKind::LessEqual) => value <= rhs,
+                    ExprKind::Operator(TokenKind::Greater) => value > rhs,
+                    ExprKind::Operator(TokenKind::GreaterEqual) => value >= rhs,
+                    k => return Err(format!("Expected a comparison, found {:?}", k)),
+                };
+                value = holds as u16;
+            }
+            Ok(value)
+        }
+        ExprKind::Expression | ExprKind::Primary => evaluate(&expr.exprs[0], symbols),
+        ExprKind::Term | ExprKind::Factor => {
+            let mut value = evaluate(&expr.exprs[0], symbols)?;
+            for pair in expr.exprs[1..].chunks(2) {
+                let rhs = evaluate(&pair[1], symbols)?;
+                value = match &pair[0].kind {
+                    ExprKind::Operator(TokenKind::Plus) => value.wrapping_add(rhs),
+                    ExprKind::Operator(TokenKind::Minus) => value.wrapping_sub(rhs),
+                    ExprKind::Operator(TokenKind::Times) => value.wrapping_mul(rhs),
+                    ExprKind::Operator(TokenKind::Div) => match value.checked_div(rhs) {
+                        Some(v) => v,
+                        None => return Err("Division by zero".to_owned()),
+                    },
+                    k => return Err(format!("Expected an operator, found {:?}", k)),
+                };
+            }
+            Ok(value)
+        }
+        ExprKind::Unary => match &expr.exprs[0].kind {
+            ExprKind::Operator(TokenKind::Minus) => {
+                Ok(evaluate(&expr.exprs[1], symbols)?.wrapping_neg())
+            }
+            ExprKind::Operator(_) => evaluate(&expr.exprs[1], symbols),
+            _ => evaluate(&expr.exprs[0], symbols),
+        },
+        ExprKind::Integer(n) => Ok(*n),
+        ExprKind::Label(l) => match symbols.get(l) {
+            Some(address) => Ok(*address),
+            None => Err(format!("Unknown label '{}'", l)),
+        },
+        k => Err(format!("Expected a value, found {:?}", k)),
+    }
+}
@@ -0,0 +1,153 @@
+use basm::fileio::Input;
+use basm::term::{ColorChoice, Verbosity};
+use basm_cli::args::{option_value, set_input, short_value};
+
+const USAGE: &str = "\
+Usage: basm [OPTIONS] <FILE>
+
+Arguments:
+  <FILE>                 Source file to assemble, or `-` to read from stdin
+
+Options:
+  -s                     Read the source from stdin (same as `-`)
+  -o <PATH>              Write output to PATH instead of stdout
+      --format <FORMAT>  Output format: ast or tokens [default: ast]
+      --listing <PATH>   Write a listing of every assembled statement and its source line to PATH
+      --map <PATH>       Read the code map from PATH [default: <FILE>.map, if it exists]
+      --suffix-conditions
+                         Also read conditions written straight after an instruction, like
+                         `jmpnz`, which stops labels like `jmpz` being labels
+  -q, --quiet            Only print errors
+  -v, --verbose          Print progress information
+      --color <WHEN>     Color diagnostics: auto, always or never [default: auto]
+  -h, --help             Print this help and exit
+  -V, --version          Print version information and exit";
+
+/// What basm writes as its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ast,    // The assembled statements
+    Tokens, // The lexer's output, before parsing
+}
+
+/// A fully interpreted command line
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub input: Input,
+    pub output: Option<String>,
+    pub format: Format,
+    pub listing: Option<String>,
+    pub map: Option<String>,
+    pub suffix_conditions: bool,
+    pub verbosity: Verbosity,
+    pub color: ColorChoice,
+}
+
+/// What main should do after the command line has been read
+#[derive(Debug)]
+pub enum Command {
+    Run(Args),
+    Help,
+    Version,
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+pub fn version() -> String {
+    format!("basm {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Interprets command line arguments, not including the program name
+pub fn parse_args<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+
+    let mut input = None;
+    let mut output = None;
+    let mut format = Format::Ast;
+    let mut listing = None;
+    let mut map = None;
+    let mut suffix_conditions = false;
+    let mut verbosity = Verbosity::Normal;
+    let mut color = ColorChoice::Auto;
+    let mut only_positional = false;
+
+    while let Some(arg) = args.next() {
+        if only_positional || arg == "-" || !arg.starts_with('-') {
+            let new_input = if arg == "-" {
+                Input::Stdin
+            } else {
+                Input::File(arg)
+            };
+            set_input(&mut input, new_input)?;
+            continue;
+        }
+
+        // Split `--long=value` so the value can be handled like `--long value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+
+        match flag.as_str() {
+            "--" => only_positional = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-s" => set_input(&mut input, Input::Stdin)?,
+            "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => verbosity = Verbosity::Verbose,
+            "--format" => {
+                format = match option_value(&flag, inline_value, &mut args)?.as_str() {
+                    "ast" => Format::Ast,
+                    "tokens" => Format::Tokens,
+                    s => {
+                        return Err(format!(
+                            "Invalid value '{}' for --format (expected ast or tokens)",
+                            s
+                        ))
+                    }
+                }
+            }
+            "--listing" => listing = Some(option_value(&flag, inline_value, &mut args)?),
+            "--map" => map = Some(option_value(&flag, inline_value, &mut args)?),
+            "--suffix-conditions" => suffix_conditions = true,
+            "--color" => {
+                color = match option_value(&flag, inline_value, &mut args)?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    s => {
+                        return Err(format!(
+                            "Invalid value '{}' for --color (expected auto, always or never)",
+                            s
+                        ))
+                    }
+                }
+            }
+            _ if flag.starts_with("-o") => output = Some(short_value("-o", &flag, &mut args)?),
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    let input = match input {
+        Some(i) => i,
+        None => return Err("Expected an input file, or `-` for stdin".to_owned()),
+    };
+
+    Ok(Command::Run(Args {
+        input,
+        output,
+        format,
+        listing,
+        map,
+        suffix_conditions,
+        verbosity,
+        color,
+    }))
+}
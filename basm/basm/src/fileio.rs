@@ -1,25 +1,32 @@
-use std::env;
 use std::fs::File;
 use std::io;
-use std::io::prelude::{Read, /*Write*/};
-use crate::codemap::CodeMap;
-
-pub fn get_input() -> Result<(String, Option<CodeMap>), String> {
-    // Get command line arguments
-    let args: Vec<String> = env::args().collect();
-
-    // Interpret arguments
-    match args.len() {
-        1 => return Err("Expected at least one command line argument".to_owned()),
-        2 => match &args[1][0..=1] {
-            "-s" => return get_std(), // -s indicates that the file comes from stdin
-            _ => {}                   // Assume argument is filename and move on
-        },
-        _ => return Err("Too many arguments provided".to_owned()),
+use std::io::prelude::{Read, Write};
+use basm_codemap::CodeMap;
+use crate::term;
+
+/// Where the source to be assembled comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(String),
+}
+
+/// Reads the source selected on the command line, along with its code map if one can be found.
+/// An explicitly given map path must exist, while the default `<FILE>.map` is optional.
+pub fn get_input(input: &Input, map_path: Option<&str>) -> Result<(String, Option<CodeMap>), String> {
+    let (asm, default_map) = match input {
+        Input::Stdin => (get_std()?, None),
+        Input::File(filename) => (get_asm(filename)?, Some(format!("{}.map", filename))),
     };
 
-    let asm = get_asm(&args[1])?;
-    let map = get_map(&args[1])?;
+    let map = match (map_path, default_map) {
+        (Some(path), _) => Some(get_map(path)?),
+        (None, Some(path)) => match get_file(&path) {
+            Ok(_) => Some(get_map(&path)?),
+            Err(_) => None,
+        },
+        (None, None) => None,
+    };
 
     Ok((asm, map))
 }
@@ -28,42 +35,66 @@ pub fn get_asm(filename: &str) -> Result<String, String> {
     get_content(get_file(filename)?)
 }
 
-pub fn get_map(filename: &str) -> Result<Option<CodeMap>, String> {
-    let filename = format!("{}.map", filename);
-    let mut file = match get_file(filename.as_str()) {
-        Ok(f) => f,
-        Err(_) => return Ok(None),
-    };
+pub fn get_map(filename: &str) -> Result<CodeMap, String> {
+    let mut file = get_file(filename)?;
     let mut data = String::new();
-    
+
     match file.read_to_string(&mut data) {
         Ok(_) => {},
         Err(_) => return Err(format!("Couldn't read {filename}")),
     };
-    
-    match serde_json::from_str(&data) {
-        Ok(map_box) => Ok(map_box),
-        Err(e) => Err(format!("Couldn't deserialize map file \"{filename}\". Error: {e}")),
-    }
+
+    CodeMap::from_json(&data).map_err(|e| format!("Map file \"{filename}\": {e}"))
+}
+
+/// Writes data to the given file, or to stdout if there's no filename
+pub fn write_output(filename: Option<&str>, data: &[u8]) -> Result<(), String> {
+    let filename = match filename {
+        Some(f) => f,
+        None => {
+            let mut stdout = io::stdout().lock();
+            if let Err(e) = stdout.write_all(data).and_then(|_| stdout.flush()) {
+                return Err(format!("Couldn't write to stdout, error:\n  {}", e));
+            }
+            return Ok(());
+        }
+    };
+
+    let mut file = match File::create(filename) {
+        Ok(f) => f,
+        Err(e) => return Err(format!(
+            "{} couldn't be created. File::create(...) returned the following error:\n  {}",
+            filename,
+            e,
+        )),
+    };
+
+    if let Err(e) = file.write_all(data) {
+        return Err(format!(
+            "{} couldn't be written to. file.write_all(...) returned the following error:\n  {}",
+            filename,
+            e,
+        ));
+    };
+
+    Ok(())
 }
 
-fn get_std() -> Result<(String, Option<CodeMap>), String> {
+fn get_std() -> Result<String, String> {
     let stdin = io::stdin();
     let mut data = String::new();
     match stdin.lock().read_to_string(&mut data) {
-        Ok(n) => println!("\x1b[95mBASM\x1b[90m: {n} bytes read from stdin."),
+        Ok(n) => term::info(&format!("{n} bytes read from stdin.")),
         Err(e) => return Err(format!("Couldn't read from stdin, error:\n  {}", e)),
     }
-    Ok((data, None)) // Return read file plus no codemap
+    Ok(data)
 }
 
 fn get_file(filename: &str) -> Result<File, String> {
     // Ensure file can be opened, and if not, return error
     match File::open(filename) {
         Ok(file) => Ok(file),
-        Err(_) => {
-            return Err(format!("{} Could not be opened.", filename));
-        }
+        Err(_) => Err(format!("{} Could not be opened.", filename)),
     }
 }
 
@@ -71,9 +102,9 @@ fn get_content(mut file: File) -> Result<String, String> {
     let mut data = String::new(); // Create string buffer to hold the contents of the file
 
     // Ensure opened file can be read, and if not, return error
-    if let Err(_) = file.read_to_string(&mut data) {
+    if file.read_to_string(&mut data).is_err() {
         return Err("Could not be read.".to_owned());
     };
 
     Ok(data)
-}
\ No newline at end of file
+}
@@ -0,0 +1,11 @@
+use crate::parser::Expr;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An assembled program: the statements that are assembled, in order, with the labels they
+/// define and the value of every constant. It holds no machine code yet.
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    pub statements: Vec<Expr>, // Macros, pseudo-instructions and .if are already dealt with
+    pub labels: BTreeSet<String>,
+    pub constants: BTreeMap<String, u16>, // Defined with .equ
+}
@@ -1,3 +1,6 @@
+use crate::pseudo;
+use basm_codemap::CodeMap;
+
 #[derive(Debug, Clone)]
 pub enum TokenKind {
     // Types
@@ -13,6 +16,12 @@ pub enum TokenKind {
     Minus,
     Times,
     Div,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     Colon, // For denoting labels
 
     // Keywords
@@ -54,6 +63,16 @@ pub enum TokenKind {
     Sec,
     Sez,
 
+    // Pseudo-instructions, which stand for one or more of the instructions above
+    Nop,
+    Push,
+    Pop,
+    Neg,
+    Not,
+    Call,
+    Tail,
+    Li,
+
     // Conditionals
     C,
     Z,
@@ -65,6 +84,17 @@ pub enum TokenKind {
     // Assembler directives
     Org,
     Db,
+    Macro,
+    Endm,
+    Rept,
+    Irp,
+    Endr,
+    Equ,
+    If,
+    Ifdef,
+    Else,
+    Endif,
+    Assert,
 
     //Other
     None,
@@ -77,11 +107,22 @@ pub struct Token {
     pub line: usize,
 }
 
+/// A `#line N "file"` marker, saying that the line after it is line N of file
+#[derive(Debug)]
+struct LineMarker {
+    line: usize,        // The line the marker is on
+    target_line: usize, // N
+    filename: String,
+}
+
 #[derive(Debug)]
 pub struct Lexer<'a> {
     data: &'a str,
     span: (usize, usize),
     line: usize,
+    markers: Vec<LineMarker>,
+    /// Whether conditions can be written straight after an instruction, like `jmpnz`
+    pub suffix_conditions: bool,
 }
 
 /// Returns a portion of a data from the start until pred returns false
@@ -184,10 +225,6 @@ fn tokenize_string_literal(data: &str) -> Result<Token, String> {
         final_string.push(next);
     }
 
-    if bytes_read == 0 {
-        return Err("No matches".to_owned());
-    }
-
     Ok(Token {
         kind: TokenKind::String(final_string),
         span: bytes_read + 2,
@@ -228,6 +265,14 @@ fn tokenize_identifier(data: &str) -> Result<Token, String> {
         "clz" => TokenKind::Clz,
         "sec" => TokenKind::Sec,
         "sez" => TokenKind::Sez,
+        "nop" => TokenKind::Nop,
+        "push" => TokenKind::Push,
+        "pop" => TokenKind::Pop,
+        "neg" => TokenKind::Neg,
+        "not" => TokenKind::Not,
+        "call" => TokenKind::Call,
+        "tail" => TokenKind::Tail,
+        "li" => TokenKind::Li,
         "c" => TokenKind::C,
         "z" => TokenKind::Z,
         "nc" => TokenKind::Nc,
@@ -250,6 +295,25 @@ fn tokenize_identifier(data: &str) -> Result<Token, String> {
     })
 }
 
+/// Tokenizes a comparison operator from its first character and the one after it
+fn tokenize_comparison(first: char, second: char) -> Result<Token, String> {
+    let (kind, span) = match (first, second) {
+        ('=', '=') => (TokenKind::Equal, 2),
+        ('!', '=') => (TokenKind::NotEqual, 2),
+        ('<', '=') => (TokenKind::LessEqual, 2),
+        ('>', '=') => (TokenKind::GreaterEqual, 2),
+        ('<', _) => (TokenKind::Less, 1),
+        ('>', _) => (TokenKind::Greater, 1),
+        (c, _) => return Err(format!("Unexpected character {}, did you mean {}=?", c, c)),
+    };
+
+    Ok(Token {
+        kind,
+        span,
+        line: 0,
+    })
+}
+
 /// Tokenizes a single directive
 fn tokenize_directive(data: &str) -> Result<Token, String> {
     let (read, bytes_read) = take_while(data, |c| c == '_' || c == '.' || c.is_alphanumeric())?;
@@ -257,6 +321,17 @@ fn tokenize_directive(data: &str) -> Result<Token, String> {
     let token_kind = match &read.to_lowercase()[..] {
         ".org" => TokenKind::Org,
         ".db" => TokenKind::Db,
+        ".macro" => TokenKind::Macro,
+        ".endm" => TokenKind::Endm,
+        ".rept" => TokenKind::Rept,
+        ".irp" => TokenKind::Irp,
+        ".endr" => TokenKind::Endr,
+        ".equ" => TokenKind::Equ,
+        ".if" => TokenKind::If,
+        ".ifdef" => TokenKind::Ifdef,
+        ".else" => TokenKind::Else,
+        ".endif" => TokenKind::Endif,
+        ".assert" => TokenKind::Assert,
         s => return Err(format!("Unknown dot directive '{}'.", s)),
     };
 
@@ -267,6 +342,84 @@ fn tokenize_directive(data: &str) -> Result<Token, String> {
     })
 }
 
+/// Reads a `#line N "file"` marker from the start of data, returning N, the filename, and the
+/// number of bytes read. The marker has to be the only thing on its line, which the lexer checks
+/// for the text before it.
+fn tokenize_line_marker(data: &str) -> Result<(usize, String, usize), String> {
+    let (read, mut bytes_read) = take_while(data, |c| c == '#' || c.is_alphanumeric())?;
+    if read != "#line" {
+        return Err(format!("Unknown marker '{}', expected '#line'", read));
+    }
+    bytes_read += skip_white_space(&data[bytes_read..]);
+
+    let line = match tokenize_number(&data[bytes_read..]) {
+        Ok(Token {
+            kind: TokenKind::Integer(n),
+            span,
+            ..
+        }) if n > 0 => {
+            bytes_read += span;
+            n as usize
+        }
+        _ => return Err("#line expects a line number".to_owned()),
+    };
+    bytes_read += skip_white_space(&data[bytes_read..]);
+
+    let filename = match tokenize_string_literal(&data[bytes_read..]) {
+        Ok(Token {
+            kind: TokenKind::String(s),
+            span,
+            ..
+        }) if !s.is_empty() => {
+            bytes_read += span;
+            s
+        }
+        _ => return Err("#line expects a filename string after the line number".to_owned()),
+    };
+    bytes_read += skip_white_space(&data[bytes_read..]);
+
+    if !data[bytes_read..].is_empty() && !data[bytes_read..].starts_with('\n') {
+        return Err("Unexpected text after #line marker".to_owned());
+    }
+
+    Ok((line, filename, bytes_read))
+}
+
+/// Reads an instruction with its condition written after it, like `jmp.nz`, returning the
+/// instruction, the condition and the number of bytes read. With undotted, `jmpnz` is read the same
+/// way, though that turns labels like `jmpz` or `addc` into instructions.
+fn tokenize_suffixed_condition(data: &str, undotted: bool) -> Option<(TokenKind, TokenKind, usize)> {
+    let keyword = |word: &str| tokenize_identifier(word).ok().map(|t| t.kind);
+    let is_condition = |kind: &TokenKind| {
+        matches!(
+            kind,
+            TokenKind::C | TokenKind::Z | TokenKind::Nc | TokenKind::Nz | TokenKind::Cz | TokenKind::Ncz
+        )
+    };
+    let is_op = |kind: &TokenKind| kind.is_opcode() || pseudo::is_pseudo(kind);
+
+    let (word, bytes_read) = take_while(data, |c| c == '_' || c.is_alphanumeric()).ok()?;
+
+    if let Some(rest) = data[bytes_read..].strip_prefix('.') {
+        let (suffix, suffix_len) = take_while(rest, |c| c == '_' || c.is_alphanumeric()).ok()?;
+        let (op, condition) = (keyword(word)?, keyword(suffix)?);
+        return match is_op(&op) && is_condition(&condition) {
+            true => Some((op, condition, bytes_read + 1 + suffix_len)),
+            false => None,
+        };
+    }
+
+    if !undotted {
+        return None;
+    }
+
+    (1..word.len())
+        .filter(|&i| word.is_char_boundary(i))
+        .filter_map(|i| Some((keyword(&word[..i])?, keyword(&word[i..])?)))
+        .find(|(op, condition)| is_op(op) && is_condition(condition))
+        .map(|(op, condition)| (op, condition, bytes_read))
+}
+
 /// Tokenizes any character, string, integer, keyword, label, etc. Does not skip comments or whitespace
 pub fn tokenize_one_token(data: &str) -> Result<Token, String> {
     let mut chars = data.chars();
@@ -274,7 +427,7 @@ pub fn tokenize_one_token(data: &str) -> Result<Token, String> {
         Some(c) => c,
         None => return Err("Unexpected EOF".to_owned()),
     };
-    //let peek = chars.next().unwrap_or('\0');
+    let peek = chars.next().unwrap_or('\0');
 
     let token = match next {
         ',' => Token {
@@ -312,6 +465,7 @@ pub fn tokenize_one_token(data: &str) -> Result<Token, String> {
             span: 1,
             line: 0,
         },
+        '=' | '!' | '<' | '>' => tokenize_comparison(next, peek)?,
         ':' => Token {
             kind: TokenKind::Colon,
             span: 1,
@@ -334,12 +488,14 @@ impl<'a> Lexer<'a> {
             data,
             span: (0, data.len()),
             line: 1,
+            markers: Vec::new(),
+            suffix_conditions: false,
         }
     }
 
     /// Tokenizes all of self.data, returning a Vec of all the tokens to be passed to a parser
     pub fn tokenize(&mut self) -> Result<Vec<Token>, (String, usize)> {
-        let mut tokens = Vec::new();
+        let mut tokens: Vec<Token> = Vec::new();
 
         while self.span.0 != self.span.1 {
             let (val, consumed) = match self.data.chars().nth(self.span.0).unwrap_or_else(|| {
@@ -352,13 +508,41 @@ impl<'a> Lexer<'a> {
                     (TokenKind::None, skip_white_space(self.get_selected()))
                 }
                 ';' => (TokenKind::None, skip_comment(self.get_selected())),
+                // A marker after other text would remap the lines after it without anyone noticing
+                '#' if tokens.last().is_some_and(|t| t.line == self.line) => {
+                    return Err(("#line has to be at the start of its line".to_owned(), self.line))
+                }
+                '#' => match tokenize_line_marker(self.get_selected()) {
+                    Ok((target_line, filename, consumed)) => {
+                        self.markers.push(LineMarker {
+                            line: self.line,
+                            target_line,
+                            filename,
+                        });
+                        (TokenKind::None, consumed)
+                    }
+                    Err(e) => return Err((e, self.line)),
+                },
                 '\n' => {
                     self.line += 1;
                     (TokenKind::None, 1)
                 }
-                _ => match tokenize_one_token(self.get_selected()) {
-                    Ok(tok) => (tok.kind, tok.span),
-                    Err(e) => return Err((e, self.line)),
+                _ => match tokenize_suffixed_condition(self.get_selected(), self.suffix_conditions) {
+                    // Written the same as `-condition op`, so the parser sees no difference
+                    Some((op, condition, consumed)) => {
+                        for kind in [TokenKind::Minus, condition] {
+                            tokens.push(Token {
+                                kind,
+                                span: 0,
+                                line: self.line,
+                            });
+                        }
+                        (op, consumed)
+                    }
+                    None => match tokenize_one_token(self.get_selected()) {
+                        Ok(tok) => (tok.kind, tok.span),
+                        Err(e) => return Err((e, self.line)),
+                    },
                 },
             };
 
@@ -379,6 +563,33 @@ impl<'a> Lexer<'a> {
         Ok(tokens)
     }
 
+    /// Builds a code map from the `#line` markers read so far, covering every line up to the
+    /// current one. Lines before the first marker are attributed to source_name. Returns None if
+    /// there weren't any markers.
+    pub fn code_map(&self, source_name: &str) -> Option<CodeMap> {
+        if self.markers.is_empty() {
+            return None;
+        }
+
+        let mut map = CodeMap::new();
+        let mut markers = self.markers.iter().peekable();
+        let mut current = (map.filename_index(source_name), 1);
+
+        for line in 1..=self.line {
+            if let Some(marker) = markers.next_if(|m| m.line == line) {
+                // Both the marker's line and the line after it map to the marked line
+                current = (map.filename_index(&marker.filename), marker.target_line);
+                map.add_entry(current.0, current.1);
+                continue;
+            }
+
+            map.add_entry(current.0, current.1);
+            current.1 += 1;
+        }
+
+        Some(map)
+    }
+
     /// Removes amount characters from the beginning of self.data by increasing self.span.0
     fn consume(&mut self, amount: usize) {
         let (start, end) = self.span;
@@ -391,8 +602,143 @@ impl<'a> Lexer<'a> {
     }
 }
 
+impl TokenKind {
+    /// Whether the token names one of the W4096's instructions
+    pub fn is_opcode(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Mov
+                | TokenKind::Add
+                | TokenKind::Adc
+                | TokenKind::Sub
+                | TokenKind::Sbb
+                | TokenKind::Sbw
+                | TokenKind::Swb
+                | TokenKind::Nnd
+                | TokenKind::And
+                | TokenKind::Aib
+                | TokenKind::Anb
+                | TokenKind::Bia
+                | TokenKind::Bna
+                | TokenKind::Ora
+                | TokenKind::Nor
+                | TokenKind::Jmp
+                | TokenKind::Hlt
+                | TokenKind::Jsr
+                | TokenKind::Ret
+                | TokenKind::Dec
+                | TokenKind::Inc
+                | TokenKind::Cmp
+                | TokenKind::Xor
+                | TokenKind::Xnr
+                | TokenKind::Clc
+                | TokenKind::Clz
+                | TokenKind::Sec
+                | TokenKind::Sez
+        )
+    }
+
+    /// Returns the name the token stands for where a label can be. Pseudo-instructions' names can
+    /// be labels too, since the W4096 doesn't have those instructions itself.
+    pub fn label_name(&self) -> Option<String> {
+        match self {
+            TokenKind::Label(name) => Some(name.to_owned()),
+            kind if pseudo::is_pseudo(kind) => Some(kind.spelling()),
+            _ => None,
+        }
+    }
+
+    /// Returns how the token is written in basm source
+    pub fn spelling(&self) -> String {
+        match self {
+            TokenKind::String(s) => {
+                let mut escaped = String::from('"');
+                for c in s.chars() {
+                    match c {
+                        '\n' => escaped.push_str("\\n"),
+                        '\0' => escaped.push_str("\\0"),
+                        '\\' => escaped.push_str("\\\\"),
+                        '"' => escaped.push_str("\\\""),
+                        c => escaped.push(c),
+                    }
+                }
+                escaped.push('"');
+                escaped
+            }
+            TokenKind::Label(l) => l.to_owned(),
+            TokenKind::Integer(n) => n.to_string(),
+            TokenKind::Comma => ",".to_owned(),
+            TokenKind::OpenParen => "(".to_owned(),
+            TokenKind::CloseParen => ")".to_owned(),
+            TokenKind::Plus => "+".to_owned(),
+            TokenKind::Minus => "-".to_owned(),
+            TokenKind::Times => "*".to_owned(),
+            TokenKind::Div => "/".to_owned(),
+            TokenKind::Equal => "==".to_owned(),
+            TokenKind::NotEqual => "!=".to_owned(),
+            TokenKind::Less => "<".to_owned(),
+            TokenKind::LessEqual => "<=".to_owned(),
+            TokenKind::Greater => ">".to_owned(),
+            TokenKind::GreaterEqual => ">=".to_owned(),
+            TokenKind::Colon => ":".to_owned(),
+            TokenKind::Org => ".org".to_owned(),
+            TokenKind::Db => ".db".to_owned(),
+            TokenKind::Macro => ".macro".to_owned(),
+            TokenKind::Endm => ".endm".to_owned(),
+            TokenKind::Rept => ".rept".to_owned(),
+            TokenKind::Irp => ".irp".to_owned(),
+            TokenKind::Endr => ".endr".to_owned(),
+            TokenKind::Equ => ".equ".to_owned(),
+            TokenKind::If => ".if".to_owned(),
+            TokenKind::Ifdef => ".ifdef".to_owned(),
+            TokenKind::Else => ".else".to_owned(),
+            TokenKind::Endif => ".endif".to_owned(),
+            TokenKind::Assert => ".assert".to_owned(),
+            TokenKind::None => String::new(),
+            // Keywords are spelled the same as their names
+            k => format!("{:?}", k).to_lowercase(),
+        }
+    }
+}
+
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{:?}", self.kind)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_map(source: &str) -> Result<Option<CodeMap>, String> {
+        let mut lexer = Lexer::new(source);
+        lexer.tokenize().map_err(|(e, _)| e)?;
+        Ok(lexer.code_map("test.basm"))
+    }
+
+    #[test]
+    fn markers_map_the_lines_after_them() {
+        let map = code_map("mov ac, 1\n#line 10 \"main.basm\"\nmov ac, 2\ninc ac\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(map.get_from(1), ("test.basm".to_owned(), 1));
+        assert_eq!(map.get_from(3), ("main.basm".to_owned(), 10));
+        assert_eq!(map.get_from(4), ("main.basm".to_owned(), 11));
+        assert!(code_map("mov ac, 1\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_markers_after_other_text() {
+        let error = code_map("mov ac, 1 #line 5 \"x.basm\"\n").unwrap_err();
+        assert!(error.contains("start of its line"), "{}", error);
+        assert!(code_map("  #line 5 \"x.basm\"\n").unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_markers() {
+        assert!(code_map("#line 0 \"x.basm\"\n").is_err());
+        assert!(code_map("#line 5\n").is_err());
+        assert!(code_map("#line 5 \"x.basm\" mov\n").is_err());
+    }
+}
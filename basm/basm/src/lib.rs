@@ -0,0 +1,168 @@
+pub mod assembler;
+pub mod fileio;
+pub mod image;
+pub mod lexer;
+pub mod listing;
+pub mod macros;
+pub mod parser;
+pub mod pseudo;
+pub mod term;
+
+pub use basm_codemap::CodeMap;
+pub use image::Image;
+
+use lexer::{Lexer, Token};
+use parser::{Expr, Parser};
+
+/// Settings for how source is read
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Read conditions written straight after an instruction without a `.`, like `jmpnz`. Off by
+    /// default, since it turns labels like `jmpz` into instructions.
+    pub suffix_conditions: bool,
+}
+
+/// Splits source into tokens. Errors are located with the code map built from the source's
+/// `#line` markers if it has any, and otherwise with the map given, which is returned alongside
+/// the tokens for the later stages to use.
+pub fn tokenize(
+    source: &str,
+    source_name: &str,
+    map: Option<CodeMap>,
+    options: &Options,
+) -> Result<(Vec<Token>, Option<CodeMap>), String> {
+    let mut lexer = Lexer::new(source);
+    lexer.suffix_conditions = options.suffix_conditions;
+    let tokens = lexer.tokenize();
+    let map = lexer.code_map(source_name).or(map);
+
+    match tokens {
+        Ok(t) => Ok((t, map)),
+        Err((msg, line)) => Err(term::located_error(map.as_ref(), line, &msg)),
+    }
+}
+
+/// Parses tokens into a list of statements, with every macro, repetition and pseudo-instruction
+/// expanded
+pub fn parse(tokens: Vec<Token>, map: Option<&CodeMap>) -> Result<Vec<Expr>, String> {
+    Parser::new(tokens)
+        .parse()
+        .and_then(macros::expand)
+        .and_then(pseudo::expand)
+        .map_err(|(msg, line)| term::located_error(map, line, &msg))
+}
+
+/// Assembles parsed statements into an image. If several assertions fail, each one's error is on
+/// lines of its own.
+pub fn assemble_ast(ast: &[Expr], map: Option<&CodeMap>) -> Result<Image, String> {
+    assembler::assemble(ast).map_err(|errors| {
+        errors
+            .iter()
+            .map(|(msg, line)| term::located_error(map, *line, msg))
+            .collect::<Vec<String>>()
+            .join("\n")
+    })
+}
+
+/// Assembles source into an image. source_name and map are only used to say where errors are.
+pub fn assemble(
+    source: &str,
+    source_name: &str,
+    map: Option<CodeMap>,
+    options: &Options,
+) -> Result<Image, String> {
+    let (tokens, map) = tokenize(source, source_name, map, options)?;
+    let ast = parse(tokens, map.as_ref())?;
+    assemble_ast(&ast, map.as_ref())
+}
+
+/// Parses source with every macro, repetition and pseudo-instruction expanded, returning each
+/// statement written back as source. Shared by the tests of each stage.
+#[cfg(test)]
+fn parse_source(source: &str, options: &Options) -> Result<Vec<String>, String> {
+    let (tokens, map) = tokenize(source, "test.basm", None, options)?;
+    let ast = parse(tokens, map.as_ref())?;
+    Ok(ast.iter().map(|statement| statement.to_source()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suffixed() -> Options {
+        Options {
+            suffix_conditions: true,
+        }
+    }
+
+    #[test]
+    fn condition_spellings_are_the_same_instruction() {
+        let prefixed = parse_source("loop: -nz jmp loop", &suffixed()).unwrap();
+        assert_eq!(
+            parse_source("loop: jmp.nz loop", &suffixed()).unwrap(),
+            prefixed
+        );
+        assert_eq!(
+            parse_source("loop: jmpnz loop", &suffixed()).unwrap(),
+            prefixed
+        );
+
+        let image = |source| {
+            let image = assemble(source, "test.basm", None, &suffixed()).unwrap();
+            image
+                .statements
+                .iter()
+                .map(|s| s.to_source())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(image("loop: jmp.nz loop"), prefixed);
+        assert_eq!(image("loop: jmpnz loop"), prefixed);
+    }
+
+    #[test]
+    fn undotted_conditions_are_opt_in() {
+        let options = Options::default();
+        let labels = parse_source("jmpz:\nretc:\njmp jmpz", &options).unwrap();
+        assert_eq!(labels, ["jmpz", "retc", "jmp jmpz"]);
+        assert_eq!(
+            parse_source("jmp.z jmpz\njmpz:", &options).unwrap().len(),
+            2
+        );
+        assert!(parse_source("jmpz:", &suffixed()).is_err());
+    }
+
+    #[test]
+    fn any_instruction_can_be_conditional() {
+        let options = Options::default();
+        assert_eq!(
+            parse_source("-z mov ac, 1", &options).unwrap(),
+            parse_source("mov.z ac, 1", &options).unwrap()
+        );
+        assert!(assemble("-c add ac, br\n-z call 0", "test.basm", None, &options).is_ok());
+    }
+
+    #[test]
+    fn reports_every_failing_assertion() {
+        let source = ".assert SIZE < 4, \"too big\"\n.assert 1\n.assert SIZE == 0\n.equ SIZE, 8\n";
+        let error = assemble(source, "test.basm", None, &Options::default()).unwrap_err();
+        let lines: Vec<&str> = error.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", error);
+        assert!(lines[0].contains("line 1") && lines[1].contains("Assertion failed: too big"));
+        assert!(lines[2].contains("line 3") && lines[3].contains("Assertion failed: size == 0"));
+    }
+
+    // Until instructions are encoded, labels have no address to check
+    #[test]
+    fn assertions_on_labels_are_unsupported() {
+        let source = "start:\nmov ac, br\nend:\n.assert end - start <= 256\n";
+        let error = assemble(source, "test.basm", None, &Options::default()).unwrap_err();
+        assert!(error.contains("'end' is a label"), "{}", error);
+    }
+
+    #[test]
+    fn strings_can_be_empty() {
+        let statements = parse_source(".db \"\"\n.db \"a\" \"\"", &Options::default()).unwrap();
+        assert_eq!(statements, [".db \"\"", ".db \"a\" \"\""]);
+        assert!(tokenize("#line 1 \"\"\n", "test.basm", None, &Options::default()).is_err());
+    }
+}
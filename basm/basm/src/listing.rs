@@ -0,0 +1,27 @@
+use crate::image::Image;
+use crate::parser::ExprKind;
+use basm_codemap::CodeMap;
+
+/// Lists each assembled statement next to the source line it came from
+pub fn listing(image: &Image, map: Option<&CodeMap>) -> String {
+    let mut output = String::new();
+
+    for expr in &image.statements {
+        let location = match map {
+            Some(map) => {
+                let (filename, line) = map.get_from(expr.line);
+                format!("{}:{}", filename, line)
+            }
+            None => expr.line.to_string(),
+        };
+
+        let source = match &expr.kind {
+            ExprKind::Label(l) => format!("{}:", l),
+            _ => format!("    {}", expr.to_source()),
+        };
+
+        output.push_str(&format!("{:<24}{}\n", location, source));
+    }
+
+    output
+}
@@ -0,0 +1,532 @@
+//! Expands `.macro`s, `.rept`s and `.irp`s into the statements they stand for, so the assembler
+//! only ever sees plain instructions, directives and labels. Arguments are expressions rather than
+//! text, and every copy of a body gets its own names for the labels it defines.
+//!
+//! `.if` and `.equ` are worked out while expanding, in the order the expanded statements come in,
+//! so a macro can stop using itself with an `.if` and a `.rept` count can be a constant. Lines an
+//! `.if` leaves out are dropped without being expanded.
+
+use crate::assembler::{self, Symbols};
+use crate::lexer::TokenKind;
+use crate::parser::{Expr, ExprKind};
+use std::collections::{BTreeSet, HashMap};
+
+/// How deep macros can be used inside each other before expansion gives up
+const MAX_EXPANSION_DEPTH: usize = 100;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Expr>,
+}
+
+/// An .if that's being read
+struct Conditional {
+    line: usize,
+    enclosing: bool, // Whether the statements around the .if are assembled
+    holds: bool,     // Whether the branch being read is the one taken
+    has_else: bool,
+}
+
+#[derive(Default)]
+struct Expander {
+    macros: HashMap<String, Macro>,
+    expansion_count: usize,
+    depth: usize,
+    labels: BTreeSet<String>, // The labels defined so far, for .ifdef
+    constants: Symbols,       // The .equ constants defined so far and their values
+    conditionals: Vec<Conditional>,
+    conditional_base: usize, // How many .ifs were already open when the body being expanded started
+}
+
+/// Replaces every macro definition, use and repetition in ast with the statements it expands to,
+/// and leaves out the lines .if doesn't assemble
+pub fn expand(ast: Vec<Expr>) -> Result<Vec<Expr>, (String, usize)> {
+    let mut output = Vec::new();
+    let mut expander = Expander::default();
+    expander.expand_all(ast, &mut output)?;
+    expander.check_closed()?;
+    Ok(output)
+}
+
+impl Expander {
+    fn expand_all(
+        &mut self,
+        statements: Vec<Expr>,
+        output: &mut Vec<Expr>,
+    ) -> Result<(), (String, usize)> {
+        for statement in statements {
+            let line = statement.line;
+            if self.conditional(&statement)? || !self.active() {
+                continue;
+            }
+
+            match statement.kind {
+                ExprKind::MacroDef(name, params) => {
+                    if self.macros.contains_key(&name) {
+                        return Err((format!("Macro '{}' is defined more than once", name), line));
+                    }
+                    let body = block(statement.exprs, 0);
+                    self.macros.insert(name, Macro { params, body });
+                }
+                ExprKind::MacroCall(name) => {
+                    let m = match self.macros.get(&name) {
+                        Some(m) => m,
+                        None => {
+                            return Err((format!("Unknown instruction or macro '{}'", name), line))
+                        }
+                    };
+                    if statement.exprs.len() != m.params.len() {
+                        return Err((
+                            format!(
+                                "Macro '{}' expects {} arguments, but was given {}",
+                                name,
+                                m.params.len(),
+                                statement.exprs.len()
+                            ),
+                            line,
+                        ));
+                    }
+                    let (params, body) = (m.params.clone(), m.body.clone());
+                    self.repeat(body, &params, &statement.exprs, line, output)?;
+                }
+                ExprKind::Rept => {
+                    let count = assembler::constant_value(
+                        &statement.exprs[0],
+                        &self.labels,
+                        &self.constants,
+                    )
+                    .map_err(|e| {
+                        (
+                            format!(".rept needs a count that's known where it's used. {}", e),
+                            line,
+                        )
+                    })?;
+                    let body = block(statement.exprs, 1);
+                    for _ in 0..count {
+                        self.repeat(body.clone(), &[], &[], line, output)?;
+                    }
+                }
+                ExprKind::Irp(symbol) => {
+                    let mut exprs = statement.exprs.into_iter();
+                    let values = exprs.next().map(|v| v.exprs).unwrap_or_default();
+                    let body = block(exprs.collect(), 0);
+                    for value in values {
+                        self.repeat(body.clone(), &[symbol.to_owned()], &[value], line, output)?;
+                    }
+                }
+                kind => {
+                    let statement = Expr {
+                        kind,
+                        exprs: statement.exprs,
+                        line,
+                    };
+                    self.define(&statement)?;
+                    output.push(statement);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the statements being read are assembled, rather than left out by an .if
+    fn active(&self) -> bool {
+        self.conditionals
+            .last()
+            .is_none_or(|c| c.enclosing && c.holds)
+    }
+
+    /// Keeps track of .if, .ifdef, .else and .endif, returning whether statement is one of them
+    fn conditional(&mut self, statement: &Expr) -> Result<bool, (String, usize)> {
+        let line = statement.line;
+
+        match &statement.kind {
+            ExprKind::Directive(TokenKind::If | TokenKind::Ifdef) => {
+                let active = self.active();
+                let holds = active && condition(statement, &self.labels, &self.constants)?;
+                self.conditionals.push(Conditional {
+                    line,
+                    enclosing: active,
+                    holds,
+                    has_else: false,
+                });
+            }
+            ExprKind::Directive(d @ (TokenKind::Else | TokenKind::Endif)) => {
+                if !statement.exprs.is_empty() {
+                    return Err((
+                        format!("{} doesn't take anything after it", d.spelling()),
+                        line,
+                    ));
+                }
+                // An .if started outside the body being expanded has to be finished outside it too
+                let open = self.conditionals.len() > self.conditional_base;
+                match (d, self.conditionals.last_mut().filter(|_| open)) {
+                    (_, None) => {
+                        return Err((format!("{} without an .if before it", d.spelling()), line))
+                    }
+                    (TokenKind::Else, Some(c)) if c.has_else => {
+                        return Err((format!(".if on line {} already has an .else", c.line), line))
+                    }
+                    (TokenKind::Else, Some(c)) => {
+                        c.holds = !c.holds;
+                        c.has_else = true;
+                    }
+                    _ => {
+                        self.conditionals.pop();
+                    }
+                }
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Errors if an .if started in the body being expanded has no .endif in it
+    fn check_closed(&self) -> Result<(), (String, usize)> {
+        match self.conditionals.get(self.conditional_base) {
+            Some(c) => Err((".if has no .endif after it".to_owned(), c.line)),
+            None => Ok(()),
+        }
+    }
+
+    /// Notes the label or constant statement defines, so later conditions and counts can use it
+    fn define(&mut self, statement: &Expr) -> Result<(), (String, usize)> {
+        match &statement.kind {
+            ExprKind::Label(name) => {
+                self.labels.insert(name.to_owned());
+            }
+            ExprKind::Directive(TokenKind::Equ) => {
+                let (name, value) = assembler::constant(statement, &self.labels, &self.constants)?;
+                if self.labels.contains(name)
+                    || self.constants.insert(name.to_owned(), value).is_some()
+                {
+                    return Err((
+                        format!("'{}' is defined more than once", name),
+                        statement.line,
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Expands one copy of a body with the given arguments, giving the labels it defines names of
+    /// their own
+    fn repeat(
+        &mut self,
+        body: Vec<Expr>,
+        params: &[String],
+        args: &[Expr],
+        line: usize,
+        output: &mut Vec<Expr>,
+    ) -> Result<(), (String, usize)> {
+        if self.depth >= MAX_EXPANSION_DEPTH {
+            return Err((
+                format!(
+                    "Macros are used inside each other more than {} deep",
+                    MAX_EXPANSION_DEPTH
+                ),
+                line,
+            ));
+        }
+
+        self.expansion_count += 1;
+        let body = localize_labels(body, params, self.expansion_count);
+        let body = substitute_statements(&body, params, args)?;
+
+        self.depth += 1;
+        let base = std::mem::replace(&mut self.conditional_base, self.conditionals.len());
+        let result = self
+            .expand_all(body, output)
+            .and_then(|()| self.check_closed());
+        self.conditional_base = base;
+        self.depth -= 1;
+        result
+    }
+}
+
+/// Decides whether an .if or .ifdef's condition holds
+fn condition(
+    statement: &Expr,
+    labels: &BTreeSet<String>,
+    constants: &Symbols,
+) -> Result<bool, (String, usize)> {
+    let result = match (&statement.kind, statement.exprs.as_slice()) {
+        (ExprKind::Directive(TokenKind::Ifdef), [expr]) => match expr.lone_label() {
+            Some(name) => Ok(labels.contains(name) || constants.contains_key(name)),
+            None => Err(".ifdef expects the name of a label or constant".to_owned()),
+        },
+        (ExprKind::Directive(TokenKind::If), [expr])
+            if matches!(expr.kind, ExprKind::Expression) =>
+        {
+            assembler::constant_value(expr, labels, constants)
+                .map(|value| value != 0)
+                .map_err(|e| format!(".if needs a condition that's known where it's used. {}", e))
+        }
+        (ExprKind::Directive(d), _) => {
+            Err(format!("{} expects exactly one condition", d.spelling()))
+        }
+        (k, _) => Err(format!("Expected a condition, found {:?}", k)),
+    };
+
+    result.map_err(|e| (e, statement.line))
+}
+
+/// Takes the statements out of the block at index of exprs
+fn block(exprs: Vec<Expr>, index: usize) -> Vec<Expr> {
+    exprs
+        .into_iter()
+        .nth(index)
+        .map(|b| b.exprs)
+        .unwrap_or_default()
+}
+
+/// Renames the labels defined directly in body, and every use of them, so each copy of the body
+/// has its own. Labels in blocks inside body are renamed again when those are expanded, and
+/// labels named by a parameter keep the name they're given.
+fn localize_labels(body: Vec<Expr>, params: &[String], expansion: usize) -> Vec<Expr> {
+    let labels: Vec<String> = body
+        .iter()
+        .filter_map(|statement| match &statement.kind {
+            ExprKind::Label(name) if !params.contains(name) => Some(name.to_owned()),
+            _ => None,
+        })
+        .collect();
+
+    body.into_iter()
+        .map(|statement| rename(statement, &labels, expansion))
+        .collect()
+}
+
+fn rename(expr: Expr, labels: &[String], expansion: usize) -> Expr {
+    let kind = match expr.kind {
+        ExprKind::Label(name) if labels.contains(&name) => {
+            ExprKind::Label(format!("{}__asm{}", name, expansion))
+        }
+        kind => kind,
+    };
+
+    Expr {
+        kind,
+        exprs: expr
+            .exprs
+            .into_iter()
+            .map(|e| rename(e, labels, expansion))
+            .collect(),
+        line: expr.line,
+    }
+}
+
+/// Replaces each parameter in a body's statements with the argument given for it. A parameter
+/// used as a label's name takes the name it's given.
+fn substitute_statements(
+    body: &[Expr],
+    params: &[String],
+    args: &[Expr],
+) -> Result<Vec<Expr>, (String, usize)> {
+    body.iter()
+        .map(|statement| match &statement.kind {
+            ExprKind::Label(name) => match params.iter().position(|p| p == name) {
+                Some(i) => match args[i].lone_label() {
+                    Some(label) => Ok(Expr {
+                        kind: ExprKind::Label(label.to_owned()),
+                        exprs: vec![],
+                        line: statement.line,
+                    }),
+                    None => Err((
+                        format!(
+                            "'{}' is used as a label, so it has to be given a name",
+                            name
+                        ),
+                        statement.line,
+                    )),
+                },
+                None => Ok(statement.clone()),
+            },
+            _ => substitute(statement, params, args),
+        })
+        .collect()
+}
+
+/// Replaces each parameter in expr with the argument given for it. Arguments that are constant
+/// are put in as their value.
+fn substitute(expr: &Expr, params: &[String], args: &[Expr]) -> Result<Expr, (String, usize)> {
+    let arg = |name: &str| params.iter().position(|p| p == name).map(|i| &args[i]);
+
+    match &expr.kind {
+        // An operand that's only a parameter can be given a register or reference too
+        ExprKind::Expression => {
+            if let Some(arg) = expr.lone_label().and_then(arg) {
+                return Ok(arg.clone());
+            }
+        }
+        ExprKind::Label(name) => {
+            if let Some(arg) = arg(name) {
+                if !matches!(arg.kind, ExprKind::Expression) {
+                    return Err((
+                        format!(
+                            "'{}' is given a register or reference, so it can't be part of a value",
+                            name
+                        ),
+                        expr.line,
+                    ));
+                }
+                return Ok(match assembler::evaluate(arg, &Symbols::new()) {
+                    Ok(n) => Expr {
+                        kind: ExprKind::Integer(n),
+                        exprs: vec![],
+                        line: expr.line,
+                    },
+                    Err(_) => arg.clone(),
+                });
+            }
+        }
+        ExprKind::Block => {
+            return Ok(Expr {
+                kind: ExprKind::Block,
+                exprs: substitute_statements(&expr.exprs, params, args)?,
+                line: expr.line,
+            })
+        }
+        _ => {}
+    }
+
+    Ok(Expr {
+        kind: expr.kind.clone(),
+        exprs: expr
+            .exprs
+            .iter()
+            .map(|e| substitute(e, params, args))
+            .collect::<Result<_, _>>()?,
+        line: expr.line,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_source, Options};
+
+    #[test]
+    fn expands_macros_with_their_arguments() {
+        let source = "\
+.macro load r, n
+mov r, n * 2
+.endm
+load ac, 1 + 2
+load br, 4
+";
+        // Arguments are put in as values, not pasted in as text
+        assert_eq!(
+            parse_source(source, &Options::default()).unwrap(),
+            ["mov ac, 3 * 2", "mov br, 4 * 2"]
+        );
+    }
+
+    #[test]
+    fn repeats_rept_and_irp_bodies() {
+        assert_eq!(
+            parse_source(".rept 2\ninc ac\n.endr\n", &Options::default()).unwrap(),
+            ["inc ac", "inc ac"]
+        );
+        assert!(
+            parse_source(".rept 0\ninc ac\n.endr\n", &Options::default())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            parse_source(".irp r, ac, br, ix\ninc r\n.endr\n", &Options::default()).unwrap(),
+            ["inc ac", "inc br", "inc ix"]
+        );
+    }
+
+    #[test]
+    fn each_expansion_gets_its_own_labels() {
+        let source = "\
+.macro wait
+loop: dec ac
+jmp.nz loop
+.endm
+wait
+wait
+";
+        assert_eq!(
+            parse_source(source, &Options::default()).unwrap(),
+            [
+                "loop__asm1",
+                "dec ac",
+                "-nz jmp loop__asm1",
+                "loop__asm2",
+                "dec ac",
+                "-nz jmp loop__asm2"
+            ]
+        );
+
+        // A label named by a parameter keeps the name it's given
+        let statements = parse_source(
+            ".macro entry name\nname: nop\n.endm\nentry start\n",
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(statements, ["start", "mov ac, ac"]);
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_arguments() {
+        let error = parse_source(".macro m a, b\n.endm\nm 1\n", &Options::default()).unwrap_err();
+        assert!(
+            error.contains("expects 2 arguments, but was given 1"),
+            "{}",
+            error
+        );
+        let error = parse_source(".macro m\n.endm\nm 1\n", &Options::default()).unwrap_err();
+        assert!(
+            error.contains("expects 0 arguments, but was given 1"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn macros_can_stop_using_themselves_with_if() {
+        let source = "\
+.macro count n
+.if n > 0
+inc ac
+count n - 1
+.endif
+.endm
+count 3
+";
+        assert_eq!(
+            parse_source(source, &Options::default()).unwrap(),
+            ["inc ac", "inc ac", "inc ac"]
+        );
+
+        let error = parse_source(".macro m\nm\n.endm\nm\n", &Options::default()).unwrap_err();
+        assert!(error.contains("100"), "{}", error);
+    }
+
+    #[test]
+    fn counts_and_conditions_use_constants_defined_before_them() {
+        let source = ".equ N, 2\n.rept N\ndec ac\n.endr\n.if N == 2\nnop\n.endif\n";
+        let statements = parse_source(source, &Options::default()).unwrap();
+        assert_eq!(statements[1..], ["dec ac", "dec ac", "mov ac, ac"]);
+
+        let error = parse_source(".rept N\n.endr\n.equ N, 2\n", &Options::default()).unwrap_err();
+        assert!(error.contains("Unknown label 'n'"), "{}", error);
+    }
+
+    #[test]
+    fn ifs_end_in_the_body_they_start_in() {
+        assert!(parse_source(".macro m\n.if 1\n.endm\nm\n.endif\n", &Options::default()).is_err());
+        assert!(parse_source(".if 1\n.macro m\n.endif\n.endm\nm\n", &Options::default()).is_err());
+        assert!(
+            parse_source(".if 0\nnot_a_macro\n.endif\n", &Options::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
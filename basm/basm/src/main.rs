@@ -1,48 +1,73 @@
-mod codemap;
-mod fileio;
-mod lexer;
-mod parser;
+mod cli;
 
-fn main() {
-    // Get input data
-    let (program, map) = match fileio::get_input() {
-        Ok(s) => s,
+use basm::fileio::Input;
+use basm::{fileio, listing, term};
+use std::process::ExitCode;
+
+const EXIT_FAILURE: u8 = 1; // Something went wrong while assembling
+const EXIT_USAGE: u8 = 2; // The command line couldn't be understood
+
+fn main() -> ExitCode {
+    // Interpret command line arguments
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(args)) => args,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return ExitCode::SUCCESS;
+        }
+        Ok(cli::Command::Version) => {
+            println!("{}", cli::version());
+            return ExitCode::SUCCESS;
+        }
         Err(e) => {
-            println!("\x1b[95mBASM:\x1b[0m {}", e);
-            return;
+            term::set_color(term::ColorChoice::Auto);
+            term::error(&format!("{}\n\n{}", e, cli::usage()));
+            return ExitCode::from(EXIT_USAGE);
         }
     };
 
-    // Create lexer from input data and convert it into smaller parts for processing
-    let tokens = match lexer::Lexer::new(&program).tokenize() {
-        Ok(t) => t,
-        Err((msg, line)) => {
-            if let Some(map) = map {
-                let (filename, line) = map.get_from(line);
-                println!("\x1b[95mBASM:\x1b[0m Error on line {} of {}:\n  {}", line, filename, msg);
-            }
-            println!("\x1b[95mBASM:\x1b[0m Error on line {}:\n  {}", line, msg);
-            return;
-        }
-    };
+    term::set_color(args.color);
+    term::set_verbosity(args.verbosity);
 
-    //for tok in tokens.iter() {
-    //    println!("{}", tok);
-    //}
-
-    let ast = match parser::Parser::new(tokens).parse() {
-        Ok(t) => t,
-        Err((msg, line)) => {
-            if let Some(map) = map {
-                let (filename, line) = map.get_from(line);
-                println!("\x1b[95mBASM:\x1b[0m Error on line {} of {}:\n  {}", line, filename, msg);
-            }
-            println!("\x1b[95mBASM:\x1b[0m Error on line {}:\n  {}", line, msg);
-            return;
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            term::error(&e);
+            ExitCode::from(EXIT_FAILURE)
         }
+    }
+}
+
+fn run(args: &cli::Args) -> Result<(), String> {
+    // Get input data
+    let (program, map) = fileio::get_input(&args.input, args.map.as_deref())?;
+
+    let options = basm::Options {
+        suffix_conditions: args.suffix_conditions,
     };
+    let (tokens, map) = basm::tokenize(&program, &source_name(&args.input), map, &options)?;
+    if args.format == cli::Format::Tokens {
+        let output: String = tokens.iter().map(|tok| format!("{}\n", tok)).collect();
+        return fileio::write_output(args.output.as_deref(), output.as_bytes());
+    }
+
+    let ast = basm::parse(tokens, map.as_ref())?;
+    let image = basm::assemble_ast(&ast, map.as_ref())?;
+
+    if let Some(path) = &args.listing {
+        let text = listing::listing(&image, map.as_ref());
+        fileio::write_output(Some(path), text.as_bytes())?;
+        term::info(&format!("Listing written to {}", path));
+    }
+
+    let output: String = image.statements.iter().map(|expr| format!("{}\n", expr)).collect();
+    fileio::write_output(args.output.as_deref(), output.as_bytes())
+}
 
-    for expr in ast {
-        println!("{}", expr);
+/// Returns the name to use for the input in messages
+fn source_name(input: &Input) -> String {
+    match input {
+        Input::Stdin => "stdin".to_owned(),
+        Input::File(filename) => filename.to_owned(),
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,6 @@
 use super::lexer::{Token, TokenKind};
 use std::fmt;
+use std::mem::discriminant;
 
 #[derive(Debug)]
 pub struct Parser {
@@ -17,6 +18,12 @@ pub enum ExprKind {
     Register(TokenKind), // Registers only
     Directive(TokenKind),
 
+    Block,                         // Statements inside a .macro, .rept or .irp
+    MacroDef(String, Vec<String>), // Name and parameters, with the body in a block
+    MacroCall(String),             // Arguments only
+    Rept,                          // Count, then a block
+    Irp(String),                   // Block of values for the symbol, then a block
+
     Expression,
     Term,
     Factor,
@@ -65,21 +72,28 @@ impl Parser {
     }
 
     /*
-     *[X] statement   = instruction | directive | label
+     *[X] statement   = instruction | directive | block | label | call
      *
      *[X] instruction = op | op "?" CONDITION
      *[/] op          = OPCODE | OPCODE (hardware | expression) | OPCODE (hardware | expression), (hardware | expression)
      *[ ] hardware    = REGISTER | \(REGISTER | expression\ (+IX)?) | \(\(REGISTER | expression\ (+IX)?)\ (+IX)?)
-     *[/] expression  = term
+     *[/] expression  = term (("==" | "!=" | "<" | "<=" | ">" | ">=") term)*
      *[ ] term        = factor (("+" | "-"") factor)*
      *[ ] factor      = unary (("-" | "+") unary)*
      *[ ] unary       = ("+" | "-" | "~") unary
      *                  | primary
      *[ ] primary     = INTEGER | LABEL
      *
-     *[X] directive   = DIRECTIVE (expression | BYTE | STRING)*
+     *[X] directive   = (".org" | ".db") (expression | BYTE | STRING)*
+     *                  | DIRECTIVE ((expression | STRING) ("," (expression | STRING))*)?
+     *
+     *[X] block       = ".macro" LABEL (LABEL ("," LABEL)*)? statement* ".endm"
+     *                  | ".rept" expression statement* ".endr"
+     *                  | ".irp" LABEL ("," (hardware | expression))* statement* ".endr"
      *
      *[X] label       = LABEL ":"
+     *
+     *[X] call        = LABEL ((hardware | expression) ("," (hardware | expression))*)?
      */
 
     pub fn parse(&mut self) -> Result<Vec<Expr>, (String, usize)> {
@@ -101,8 +115,12 @@ impl Parser {
             Ok(Some(i))
         } else if let Some(d) = self.directive()? {
             Ok(Some(d))
+        } else if let Some(b) = self.block()? {
+            Ok(Some(b))
         } else if let Some(l) = self.label()? {
             Ok(Some(l))
+        } else if let Some(c) = self.macro_call()? {
+            Ok(Some(c))
         } else if let Some(t) = self.peek() {
             Err(format!("Unexpected token '{}'", t))
         } else {
@@ -124,7 +142,7 @@ impl Parser {
             // Get the next word for the condition if it exists
             let peek = match self.peek() {
                 Some(t) => t,
-                None => return Err(format!("Expected condition after '-', found EOF")),
+                None => return Err("Expected condition after '-', found EOF".to_owned()),
             };
 
             // Check that the peeked token is in fact a condition, and if so, set that to op's cond
@@ -145,10 +163,12 @@ impl Parser {
             ExprKind::Instruction(TokenKind::None)
         };
 
-        // Ensure that there's an operation to be read in
+        // Ensure that there's an operation to be read in. Any operation can take a condition, since
+        // which ones the W4096 allows isn't validated yet
         let op = match self.op()? {
             Some(op) => op,
-            None => return Ok(None),
+            None if matches!(kind, ExprKind::Instruction(TokenKind::None)) => return Ok(None),
+            None => return Err("Expected an instruction after the condition".to_owned()),
         };
 
         let line = op.line;
@@ -193,7 +213,15 @@ impl Parser {
             | TokenKind::Clc
             | TokenKind::Clz
             | TokenKind::Sec
-            | TokenKind::Sez => Expr {
+            | TokenKind::Sez
+            | TokenKind::Nop
+            | TokenKind::Push
+            | TokenKind::Pop
+            | TokenKind::Neg
+            | TokenKind::Not
+            | TokenKind::Call
+            | TokenKind::Tail
+            | TokenKind::Li => Expr {
                 kind: ExprKind::Op(op_token.kind.to_owned()),
                 exprs: vec![],
                 line: op_token.line,
@@ -201,8 +229,24 @@ impl Parser {
             _ => return Ok(None),
         };
 
+        // A pseudo-instruction's name followed by a colon is a label instead
+        if let Some(Token {
+            kind: TokenKind::Colon,
+            ..
+        }) = self.tokens.get(self.index + 1)
+        {
+            if op_token.kind.label_name().is_some() {
+                return Ok(None);
+            }
+        }
+
         self.next();
 
+        // Operands have to be on the same line as the instruction
+        if self.peek_on_line(op.line).is_none() {
+            return Ok(Some(op));
+        }
+
         match self.hardware_or_expression()? {
             Some(val) => op.exprs.push(val),
             None => return Ok(Some(op)),
@@ -326,6 +370,34 @@ impl Parser {
             None => return Ok(None),
         };
 
+        loop {
+            match self.peek() {
+                Some(t)
+                    if matches!(
+                        t.kind,
+                        TokenKind::Equal
+                            | TokenKind::NotEqual
+                            | TokenKind::Less
+                            | TokenKind::LessEqual
+                            | TokenKind::Greater
+                            | TokenKind::GreaterEqual
+                    ) =>
+                {
+                    expr.exprs.push(Expr {
+                        kind: ExprKind::Operator(t.kind.to_owned()),
+                        exprs: vec![],
+                        line: t.line,
+                    });
+                }
+                _ => break,
+            }
+            self.next();
+            match self.term()? {
+                Some(e) => expr.exprs.push(e),
+                None => return Err("Expected value after comparison operator".to_owned()),
+            }
+        }
+
         Ok(Some(expr))
     }
 
@@ -345,7 +417,8 @@ impl Parser {
         };
 
         loop {
-            match self.peek() {
+            // A '-' starting the next line is that line's condition, like `-nz jmp loop`
+            match self.peek_on_line(expr.line) {
                 Some(t) if matches!(t.kind, TokenKind::Plus | TokenKind::Minus) => {
                     expr.exprs.push(Expr {
                         kind: ExprKind::Operator(t.kind.to_owned()),
@@ -469,31 +542,22 @@ impl Parser {
                 });
                 self.next();
             }
-            Some(Token {
-                kind: TokenKind::Label(_),
-                ..
-            }) => {
-                let (kind, line) = match self.peek() {
-                    Some(Token {
-                        kind: TokenKind::Label(l),
-                        span: _,
-                        line,
-                    }) => (ExprKind::Label(l.to_owned()), *line),
-                    _ => return Ok(None),
+            Some(Token { kind, line, .. }) if kind.label_name().is_some() => {
+                let (kind, line) = match kind.label_name() {
+                    Some(l) => (ExprKind::Label(l), *line),
+                    None => return Ok(None),
                 };
 
                 self.next();
 
-                match self.peek() {
-                    Some(Token {
-                        kind: TokenKind::Colon,
-                        ..
-                    }) => {
-                        self.index -= 1;
-                        return Ok(None);
-                    }
-                    _ => (),
-                };
+                if let Some(Token {
+                    kind: TokenKind::Colon,
+                    ..
+                }) = self.peek()
+                {
+                    self.index -= 1;
+                    return Ok(None);
+                }
 
                 expr.exprs.push(Expr {
                     kind,
@@ -515,6 +579,12 @@ impl Parser {
 
         let kind = match &directive_token.kind {
             TokenKind::Org | TokenKind::Db => &directive_token.kind,
+            TokenKind::Equ
+            | TokenKind::If
+            | TokenKind::Ifdef
+            | TokenKind::Else
+            | TokenKind::Endif
+            | TokenKind::Assert => return self.line_directive().map(Some),
             _ => return Ok(None),
         };
 
@@ -557,30 +627,278 @@ impl Parser {
             None => return Ok(None),
         };
 
-        let kind = match label_token_kind {
-            TokenKind::Label(n) => ExprKind::Label(n.to_owned()),
-            _ => return Ok(None),
+        let kind = match label_token_kind.label_name() {
+            Some(n) => ExprKind::Label(n),
+            None => return Ok(None),
         };
 
         self.next();
 
-        let colon_token = match self.peek() {
-            Some(t) => t.to_owned(),
+        // Without a colon, the name is a macro being used instead
+        match self.peek() {
+            Some(t) if matches!(t.kind, TokenKind::Colon) => (),
+            _ => {
+                self.index -= 1;
+                return Ok(None);
+            }
+        };
+
+        self.next();
+
+        Ok(Some(Expr {
+            kind,
+            exprs: vec![],
+            line,
+        }))
+    }
+
+    /// Reads a directive whose arguments are separated by commas and end with its line
+    fn line_directive(&mut self) -> Result<Expr, String> {
+        let (kind, line) = match self.next() {
+            Some(t) => (ExprKind::Directive(t.kind.to_owned()), t.line),
+            None => return Err("Expected a directive, found EOF".to_owned()),
+        };
+
+        let mut directive = Expr {
+            kind,
+            exprs: vec![],
+            line,
+        };
+        if self.peek_on_line(line).is_none() {
+            return Ok(directive);
+        }
+
+        loop {
+            match self.peek() {
+                Some(Token {
+                    kind: TokenKind::String(s),
+                    span: _,
+                    line,
+                }) => {
+                    directive.exprs.push(Expr {
+                        kind: ExprKind::String(s.to_owned()),
+                        exprs: vec![],
+                        line: *line,
+                    });
+                    self.next();
+                }
+                _ => match self.expression()? {
+                    Some(expr) => directive.exprs.push(expr),
+                    None => match self.peek() {
+                        Some(t) => return Err(format!("Expected a value or string, found {}", t)),
+                        None => return Err("Expected a value or string, found EOF".to_owned()),
+                    },
+                },
+            }
+
+            if !self.comma_on_line(line) {
+                break;
+            }
+        }
+
+        Ok(directive)
+    }
+
+    fn block(&mut self) -> Result<Option<Expr>, String> {
+        let (block_kind, line) = match self.peek() {
+            Some(t) => (t.kind.to_owned(), t.line),
             None => return Ok(None),
         };
 
-        match &colon_token.kind {
-            TokenKind::Colon => (),
-            _ => return Err(format!("Unknown label {:?}", kind)),
+        let (kind, mut exprs, end) = match block_kind {
+            TokenKind::Macro => {
+                self.next();
+                let name = self.name(".macro")?;
+                let mut params = Vec::new();
+                if self.peek_on_line(line).is_some() {
+                    params.push(self.name("the macro's name")?);
+                    while self.comma_on_line(line) {
+                        params.push(self.name("','")?);
+                    }
+                }
+                (ExprKind::MacroDef(name, params), vec![], TokenKind::Endm)
+            }
+            TokenKind::Rept => {
+                self.next();
+                let count = match self.expression()? {
+                    Some(e) => e,
+                    None => return Err("Expected a count after .rept".to_owned()),
+                };
+                (ExprKind::Rept, vec![count], TokenKind::Endr)
+            }
+            TokenKind::Irp => {
+                self.next();
+                let symbol = self.name(".irp")?;
+                let mut values = Expr {
+                    kind: ExprKind::Block,
+                    exprs: vec![],
+                    line,
+                };
+                while self.comma_on_line(line) {
+                    match self.hardware_or_expression()? {
+                        Some(value) => values.exprs.push(value),
+                        None => return Err("Expected a value after ','".to_owned()),
+                    }
+                }
+                (ExprKind::Irp(symbol), vec![values], TokenKind::Endr)
+            }
+            TokenKind::Endm | TokenKind::Endr => {
+                self.next();
+                return Err(match block_kind {
+                    TokenKind::Endm => ".endm without a .macro before it".to_owned(),
+                    _ => ".endr without a .rept or .irp before it".to_owned(),
+                });
+            }
+            _ => return Ok(None),
         };
 
+        // Everything up to the matching end is the body
+        let mut body = Expr {
+            kind: ExprKind::Block,
+            exprs: vec![],
+            line,
+        };
+        loop {
+            match self.peek() {
+                Some(t) if discriminant(&t.kind) == discriminant(&end) => break,
+                Some(_) => (),
+                None => {
+                    return Err(format!(
+                        "{} on line {} has no {} after it",
+                        block_kind.spelling(),
+                        line,
+                        end.spelling()
+                    ))
+                }
+            }
+            if let Some(statement) = self.parse_one_statement()? {
+                body.exprs.push(statement);
+            }
+        }
         self.next();
 
-        Ok(Some(Expr {
+        exprs.push(body);
+        Ok(Some(Expr { kind, exprs, line }))
+    }
+
+    fn macro_call(&mut self) -> Result<Option<Expr>, String> {
+        let (kind, line) = match self.peek() {
+            Some(Token {
+                kind: TokenKind::Label(name),
+                span: _,
+                line,
+            }) => (ExprKind::MacroCall(name.to_owned()), *line),
+            _ => return Ok(None),
+        };
+
+        self.next();
+
+        // Arguments have to be on the same line as the macro's name
+        let mut call = Expr {
             kind,
             exprs: vec![],
             line,
-        }))
+        };
+        if self.peek_on_line(line).is_some() {
+            loop {
+                match self.hardware_or_expression()? {
+                    Some(arg) => call.exprs.push(arg),
+                    None => return Err("Expected an argument for the macro".to_owned()),
+                }
+                if !self.comma_on_line(line) {
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(call))
+    }
+
+    /// Reads a name, like a macro's or a parameter's, that should come after what
+    fn name(&mut self, what: &str) -> Result<String, String> {
+        match self.peek() {
+            Some(Token {
+                kind: TokenKind::Label(name),
+                ..
+            }) => {
+                let name = name.to_owned();
+                self.next();
+                Ok(name)
+            }
+            Some(t) => Err(format!("Expected a name after {}, found {}", what, t)),
+            None => Err(format!("Expected a name after {}, found EOF", what)),
+        }
+    }
+
+    fn peek_on_line(&self, line: usize) -> Option<&Token> {
+        self.peek().filter(|t| t.line == line)
+    }
+
+    /// Consumes a comma if there's one on line
+    fn comma_on_line(&mut self, line: usize) -> bool {
+        match self.peek_on_line(line) {
+            Some(t) if matches!(t.kind, TokenKind::Comma) => {
+                self.next();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Expr {
+    /// Returns the name an expression is made of, if it's nothing but a label
+    pub fn lone_label(&self) -> Option<&str> {
+        match (&self.kind, self.exprs.as_slice()) {
+            (ExprKind::Label(name), []) => Some(name),
+            (
+                ExprKind::Expression | ExprKind::Term | ExprKind::Factor | ExprKind::Unary | ExprKind::Primary,
+                [inner],
+            ) => inner.lone_label(),
+            _ => None,
+        }
+    }
+
+    /// Renders the expression back into basm syntax
+    pub fn to_source(&self) -> String {
+        let children: Vec<String> = self.exprs.iter().map(|e| e.to_source()).collect();
+
+        match &self.kind {
+            ExprKind::Instruction(TokenKind::None) => children.concat(),
+            ExprKind::Instruction(cond) => format!("-{} {}", cond.spelling(), children.concat()),
+            ExprKind::Op(op) if children.is_empty() => op.spelling(),
+            ExprKind::Op(op) => format!("{} {}", op.spelling(), children.join(", ")),
+            ExprKind::String(s) => TokenKind::String(s.to_owned()).spelling(),
+            ExprKind::Reference(true) => format!("({} + ix)", children.concat()),
+            ExprKind::Reference(false) => format!("({})", children.concat()),
+            ExprKind::Register(r) => r.spelling(),
+            ExprKind::Directive(d) if children.is_empty() => d.spelling(),
+            ExprKind::Directive(d @ (TokenKind::Org | TokenKind::Db)) => {
+                format!("{} {}", d.spelling(), children.join(" "))
+            }
+            ExprKind::Directive(d) => format!("{} {}", d.spelling(), children.join(", ")),
+            ExprKind::Expression | ExprKind::Term | ExprKind::Factor => children.join(" "),
+            ExprKind::Unary | ExprKind::Primary => children.concat(),
+            ExprKind::Integer(n) => n.to_string(),
+            ExprKind::Label(l) => l.to_owned(),
+            ExprKind::Operator(o) => o.spelling(),
+            ExprKind::Block => children.join("\n"),
+            ExprKind::MacroDef(name, params) => format!(
+                ".macro {} {}\n{}\n.endm",
+                name,
+                params.join(", "),
+                children.concat()
+            ),
+            ExprKind::MacroCall(name) if children.is_empty() => name.to_owned(),
+            ExprKind::MacroCall(name) => format!("{} {}", name, children.join(", ")),
+            ExprKind::Rept => format!(".rept {}\n{}\n.endr", children[0], children[1]),
+            ExprKind::Irp(symbol) => format!(
+                ".irp {}, {}\n{}\n.endr",
+                symbol,
+                self.exprs[0].exprs.iter().map(|e| e.to_source()).collect::<Vec<_>>().join(", "),
+                children[1]
+            ),
+        }
     }
 }
 
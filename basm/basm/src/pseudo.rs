@@ -0,0 +1,241 @@
+//! Pseudo-instructions, which the W4096 doesn't have but are easy to build from the instructions
+//! it does have:
+//!
+//!   nop          mov ac, ac
+//!   push x       mov stack, x
+//!   pop x        mov x, stack
+//!   not x        nnd x, x
+//!   neg x        nnd x, x  then  inc x
+//!   call target  jsr target
+//!   tail target  jmp target
+//!   li x, value  mov x, value
+//!
+//! The x that pop, not, neg and li write to can't be a value, and not and neg can't use stack or
+//! imm, since they read x twice. li's value can't be a register or reference.
+//!
+//! Expanding them before assembling means the listing shows the real instructions.
+
+use crate::lexer::TokenKind;
+use crate::parser::{Expr, ExprKind};
+
+/// Replaces every pseudo-instruction in ast with the instructions it stands for
+pub fn expand(ast: Vec<Expr>) -> Result<Vec<Expr>, (String, usize)> {
+    let mut output = Vec::with_capacity(ast.len());
+
+    for statement in ast {
+        let (condition, op) = match (&statement.kind, statement.exprs.first()) {
+            (ExprKind::Instruction(condition), Some(op)) => (condition, op),
+            _ => {
+                output.push(statement);
+                continue;
+            }
+        };
+        let (pseudo, operands) = match &op.kind {
+            ExprKind::Op(kind) if is_pseudo(kind) => (kind, op.exprs.as_slice()),
+            _ => {
+                output.push(statement);
+                continue;
+            }
+        };
+
+        let line = statement.line;
+        let (expected, description) = match pseudo {
+            TokenKind::Nop => (0, "no operands"),
+            TokenKind::Li => (2, "two operands"),
+            _ => (1, "one operand"),
+        };
+        if operands.len() != expected {
+            return Err((
+                format!(
+                    "{} expects {}, found {}",
+                    pseudo.spelling(),
+                    description,
+                    operands.len()
+                ),
+                line,
+            ));
+        }
+
+        check_operands(pseudo, operands).map_err(|e| (e, line))?;
+
+        let ac = || register(TokenKind::Ac, line);
+        let stack = || register(TokenKind::Stack, line);
+        let instructions = match (pseudo, operands) {
+            (TokenKind::Nop, _) => vec![(TokenKind::Mov, vec![ac(), ac()])],
+            (TokenKind::Push, [x]) => vec![(TokenKind::Mov, vec![stack(), x.clone()])],
+            (TokenKind::Pop, [x]) => vec![(TokenKind::Mov, vec![x.clone(), stack()])],
+            (TokenKind::Not, [x]) => vec![(TokenKind::Nnd, vec![x.clone(), x.clone()])],
+            (TokenKind::Neg, [x]) => vec![
+                (TokenKind::Nnd, vec![x.clone(), x.clone()]),
+                (TokenKind::Inc, vec![x.clone()]),
+            ],
+            (TokenKind::Call, [target]) => vec![(TokenKind::Jsr, vec![target.clone()])],
+            (TokenKind::Tail, [target]) => vec![(TokenKind::Jmp, vec![target.clone()])],
+            (TokenKind::Li, [x, value]) => vec![(TokenKind::Mov, vec![x.clone(), value.clone()])],
+            (k, _) => return Err((format!("{} isn't a pseudo-instruction", k.spelling()), line)),
+        };
+
+        // The first instruction could change the flags the rest would be checking
+        if instructions.len() > 1 && !matches!(condition, TokenKind::None) {
+            return Err((
+                format!(
+                    "{} can't be conditional, since it's more than one instruction",
+                    pseudo.spelling()
+                ),
+                line,
+            ));
+        }
+
+        for (opcode, operands) in instructions {
+            output.push(Expr {
+                kind: ExprKind::Instruction(condition.to_owned()),
+                exprs: vec![Expr {
+                    kind: ExprKind::Op(opcode),
+                    exprs: operands,
+                    line,
+                }],
+                line,
+            });
+        }
+    }
+
+    Ok(output)
+}
+
+pub fn is_pseudo(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Nop
+            | TokenKind::Push
+            | TokenKind::Pop
+            | TokenKind::Neg
+            | TokenKind::Not
+            | TokenKind::Call
+            | TokenKind::Tail
+            | TokenKind::Li
+    )
+}
+
+/// Errors if the operands don't make sense for what the pseudo-instruction stands for. A value
+/// has nowhere to put a result, and li only loads values.
+fn check_operands(pseudo: &TokenKind, operands: &[Expr]) -> Result<(), String> {
+    let is_value = |x: &Expr| matches!(x.kind, ExprKind::Expression);
+
+    match (pseudo, operands) {
+        (TokenKind::Pop | TokenKind::Not | TokenKind::Neg | TokenKind::Li, [x, ..])
+            if is_value(x) =>
+        {
+            Err(format!(
+                "{} needs a register or reference to put the result in, not a value",
+                pseudo.spelling()
+            ))
+        }
+        (TokenKind::Li, [_, value]) if !is_value(value) => Err(format!(
+            "li loads a value, so its second operand can't be a register or reference, found {}",
+            value.to_source()
+        )),
+        (TokenKind::Not | TokenKind::Neg, [x]) => check_repeatable(pseudo, x),
+        _ => Ok(()),
+    }
+}
+
+/// Errors if x can't be both of nnd's operands. Each read of stack or imm gets a different value,
+/// so repeating them wouldn't nnd x with itself.
+fn check_repeatable(pseudo: &TokenKind, x: &Expr) -> Result<(), String> {
+    if reads_stack_or_imm(x) {
+        return Err(format!(
+            "{} can't use stack or imm, since it reads its operand twice",
+            pseudo.spelling()
+        ));
+    }
+    Ok(())
+}
+
+fn reads_stack_or_imm(expr: &Expr) -> bool {
+    matches!(
+        expr.kind,
+        ExprKind::Register(TokenKind::Stack | TokenKind::Imm)
+    ) || expr.exprs.iter().any(reads_stack_or_imm)
+}
+
+fn register(kind: TokenKind, line: usize) -> Expr {
+    Expr {
+        kind: ExprKind::Register(kind),
+        exprs: vec![],
+        line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_source, Options};
+
+    #[test]
+    fn expands_to_real_instructions() {
+        let source = "nop\npush ac\npop br\nnot ix\nneg (5)\ncall 10\ntail 10\nli sp, 1\n";
+        assert_eq!(
+            parse_source(source, &Options::default()).unwrap(),
+            [
+                "mov ac, ac",
+                "mov stack, ac",
+                "mov br, stack",
+                "nnd ix, ix",
+                "nnd (5), (5)",
+                "inc (5)",
+                "jsr 10",
+                "jmp 10",
+                "mov sp, 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_single_instructions_can_be_conditional() {
+        assert_eq!(
+            parse_source("-z push ac\npop.nc br\n", &Options::default()).unwrap(),
+            ["-z mov stack, ac", "-nc mov br, stack"]
+        );
+        let error = parse_source("-z neg ac\n", &Options::default()).unwrap_err();
+        assert!(error.contains("more than one instruction"), "{}", error);
+    }
+
+    #[test]
+    fn names_can_be_labels() {
+        assert_eq!(
+            parse_source("push: push ac\ncall push\n", &Options::default()).unwrap(),
+            ["push", "mov stack, ac", "jsr push"]
+        );
+    }
+
+    #[test]
+    fn not_and_neg_only_repeat_what_reads_the_same_twice() {
+        for source in ["not stack", "neg imm", "not (stack)", "neg (imm + ix)"] {
+            let error = parse_source(source, &Options::default()).unwrap_err();
+            assert!(error.contains("stack or imm"), "{}: {}", source, error);
+        }
+        let error = parse_source("not 5", &Options::default()).unwrap_err();
+        assert!(error.contains("not a value"), "{}", error);
+        assert!(parse_source("not (5 + ix)", &Options::default()).is_ok());
+    }
+
+    #[test]
+    fn results_need_somewhere_to_go() {
+        for source in ["pop 5", "li 5, 1", "neg 1 + 2"] {
+            let error = parse_source(source, &Options::default()).unwrap_err();
+            assert!(error.contains("not a value"), "{}: {}", source, error);
+        }
+        assert!(parse_source("pop (5)", &Options::default()).is_ok());
+    }
+
+    #[test]
+    fn li_only_loads_values() {
+        for source in ["li ac, br", "li ac, (5)", "li ac, stack"] {
+            let error = parse_source(source, &Options::default()).unwrap_err();
+            assert!(error.contains("li loads a value"), "{}: {}", source, error);
+        }
+        assert_eq!(
+            parse_source(".equ N, 2\nli ac, N * 3\n", &Options::default()).unwrap()[1..],
+            ["mov ac, n * 3"]
+        );
+    }
+}
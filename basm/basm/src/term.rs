@@ -0,0 +1,33 @@
+use basm_codemap::CodeMap;
+
+pub use basm_cli::term::{error_at, paint, set_color, set_verbosity, ColorChoice, Verbosity};
+
+const PREFIX: &str = "BASM:";
+
+/// Formats an error message, pointing at the original source line if there's a code map. Lines
+/// written by macros also point at where each macro was used.
+pub fn located_error(map: Option<&CodeMap>, line: usize, msg: &str) -> String {
+    match map {
+        Some(map) => {
+            let (filename, source_line) = map.get_from(line);
+            let mut error = format!("{}\n  {}", error_at(Some(&filename), source_line), msg);
+            for expansion in map.expansions_of(line) {
+                error.push_str(&format!(
+                    "\n  In '{}', used at {}",
+                    expansion.name,
+                    map.expansion_location(expansion)
+                ));
+            }
+            error
+        }
+        None => format!("{}\n  {}", error_at(None, line), msg),
+    }
+}
+
+pub fn error(msg: &str) {
+    basm_cli::term::error(PREFIX, msg);
+}
+
+pub fn info(msg: &str) {
+    basm_cli::term::info(PREFIX, msg);
+}
@@ -0,0 +1,325 @@
+use basm_cli::args::{option_value, parse_define, parse_undef, set_input, short_value};
+use basm_cli::term::{ColorChoice, Verbosity};
+
+const USAGE: &str = "\
+Usage: w4096 <COMMAND> [OPTIONS] <FILE>
+
+Commands:
+  build        Preprocess and assemble FILE in one go
+  preprocess   Only preprocess FILE
+  asm          Only assemble FILE, which has already been preprocessed
+
+link, run and disasm aren't available yet, since basm doesn't write machine code.
+
+Arguments:
+  <FILE>                 Source file, or `-` to read from stdin
+
+Options:
+  -o <PATH>              Write the output to PATH, or `-` for stdout
+                         [default: stdout, or out.basm for preprocess]
+  -I <DIR>               Add DIR to the #include search path, before $W4096_INCLUDE
+                         (build, preprocess)
+  -D <NAME>[=<VALUE>]    Define NAME as VALUE (or 1) before preprocessing (build, preprocess)
+  -U <NAME>              Make sure NAME isn't defined before preprocessing, even if -D defines it
+                         (build, preprocess)
+      --map <PATH>       Where to write the code map (preprocess) or read it from (asm)
+      --line-markers     Write `#line` markers into the output (preprocess)
+      --no-line-markers  Never write `#line` markers (preprocess)
+      --trace-expansions End each line with a comment listing the macros expanded on it, in the
+                         output (preprocess) or the listing (build)
+  -M, --depfile <PATH>   Write a Make dependency file to PATH, saying the output depends on FILE
+                         and every file it includes (build, preprocess)
+      --format <FORMAT>  Output format: ast or tokens [default: ast] (build, asm)
+      --listing <PATH>   Write a listing of every assembled statement and its source line
+                         (build, asm)
+      --suffix-conditions
+                         Also read conditions written straight after an instruction, like
+                         `jmpnz` (build, asm)
+  -q, --quiet            Only print errors
+  -v, --verbose          Print progress information
+      --color <WHEN>     Color diagnostics: auto, always or never [default: auto]
+  -h, --help             Print this help and exit
+  -V, --version          Print version information and exit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    Build,
+    Preprocess,
+    Asm,
+}
+
+/// What build and asm write as their output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ast,    // The assembled statements
+    Tokens, // The lexer's output, before parsing
+}
+
+/// A fully interpreted command line. Options that don't apply to the subcommand are rejected
+/// while parsing, so each subcommand only has to look at the ones it uses.
+#[derive(Debug, Clone)]
+pub struct Args {
+    pub subcommand: Subcommand,
+    pub input: Option<String>, // None for stdin
+    pub output: Option<String>,
+    pub map: Option<String>,
+    pub line_markers: Option<bool>,
+    pub depfile: Option<String>,
+    pub trace_expansions: bool,
+    pub include_dirs: Vec<String>,
+    pub defines: Vec<(String, String)>,
+    pub undefines: Vec<String>,
+    pub format: Format,
+    pub listing: Option<String>,
+    pub suffix_conditions: bool,
+    pub verbosity: Verbosity,
+    pub color: ColorChoice,
+}
+
+/// What main should do after the command line has been read
+#[derive(Debug)]
+pub enum Command {
+    Run(Args),
+    Help,
+    Version,
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+pub fn version() -> String {
+    format!("w4096 {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Interprets command line arguments, not including the program name
+pub fn parse_args<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter();
+
+    let subcommand = match args.next().as_deref() {
+        Some("build") => Subcommand::Build,
+        Some("preprocess") => Subcommand::Preprocess,
+        Some("asm") => Subcommand::Asm,
+        Some(s @ ("link" | "run" | "disasm")) => {
+            return Err(format!(
+                "`w4096 {}` isn't available yet, since basm doesn't write machine code",
+                s
+            ))
+        }
+        Some("-h" | "--help") => return Ok(Command::Help),
+        Some("-V" | "--version") => return Ok(Command::Version),
+        Some(s) => return Err(format!("Unknown command '{}'", s)),
+        None => return Err("Expected a command".to_owned()),
+    };
+
+    let mut parsed = Args {
+        subcommand,
+        input: None,
+        output: None,
+        map: None,
+        line_markers: None,
+        depfile: None,
+        trace_expansions: false,
+        include_dirs: Vec::new(),
+        defines: Vec::new(),
+        undefines: Vec::new(),
+        format: Format::Ast,
+        listing: None,
+        suffix_conditions: false,
+        verbosity: Verbosity::Normal,
+        color: ColorChoice::Auto,
+    };
+    let mut input = None;
+    let mut only_positional = false;
+
+    while let Some(arg) = args.next() {
+        if only_positional || arg == "-" || !arg.starts_with('-') {
+            set_input(&mut input, arg)?;
+            continue;
+        }
+
+        // Split `--long=value` so the value can be handled like `--long value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_owned(), Some(value.to_owned()))
+            }
+            _ => (arg.clone(), None),
+        };
+
+        match flag.as_str() {
+            "--" => only_positional = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-s" => set_input(&mut input, "-".to_owned())?,
+            "-q" | "--quiet" => parsed.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => parsed.verbosity = Verbosity::Verbose,
+            "--color" => {
+                parsed.color = match option_value(&flag, inline_value, &mut args)?.as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    s => {
+                        return Err(format!(
+                            "Invalid value '{}' for --color (expected auto, always or never)",
+                            s
+                        ))
+                    }
+                }
+            }
+            "--map" => {
+                only_for(
+                    &flag,
+                    subcommand,
+                    &[Subcommand::Preprocess, Subcommand::Asm],
+                )?;
+                parsed.map = Some(option_value(&flag, inline_value, &mut args)?);
+            }
+            "--line-markers" | "--no-line-markers" => {
+                only_for(&flag, subcommand, &[Subcommand::Preprocess])?;
+                parsed.line_markers = Some(flag == "--line-markers");
+            }
+            "--trace-expansions" => {
+                only_for(
+                    &flag,
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed.trace_expansions = true;
+            }
+            "--depfile" => {
+                only_for(
+                    &flag,
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed.depfile = Some(option_value(&flag, inline_value, &mut args)?);
+            }
+            "--format" => {
+                only_for(&flag, subcommand, &[Subcommand::Build, Subcommand::Asm])?;
+                parsed.format = match option_value(&flag, inline_value, &mut args)?.as_str() {
+                    "ast" => Format::Ast,
+                    "tokens" => Format::Tokens,
+                    s => {
+                        return Err(format!(
+                            "Invalid value '{}' for --format (expected ast or tokens)",
+                            s
+                        ))
+                    }
+                }
+            }
+            "--listing" => {
+                only_for(&flag, subcommand, &[Subcommand::Build, Subcommand::Asm])?;
+                parsed.listing = Some(option_value(&flag, inline_value, &mut args)?);
+            }
+            "--suffix-conditions" => {
+                only_for(&flag, subcommand, &[Subcommand::Build, Subcommand::Asm])?;
+                parsed.suffix_conditions = true;
+            }
+            _ if flag.starts_with("-o") => {
+                parsed.output = Some(short_value("-o", &flag, &mut args)?);
+            }
+            _ if flag.starts_with("-M") => {
+                only_for(
+                    "-M",
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed.depfile = Some(short_value("-M", &flag, &mut args)?);
+            }
+            _ if flag.starts_with("-I") => {
+                only_for(
+                    "-I",
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed
+                    .include_dirs
+                    .push(short_value("-I", &flag, &mut args)?);
+            }
+            _ if flag.starts_with("-D") => {
+                only_for(
+                    "-D",
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed
+                    .defines
+                    .push(parse_define(&short_value("-D", &flag, &mut args)?)?);
+            }
+            _ if flag.starts_with("-U") => {
+                only_for(
+                    "-U",
+                    subcommand,
+                    &[Subcommand::Build, Subcommand::Preprocess],
+                )?;
+                parsed
+                    .undefines
+                    .push(parse_undef(&short_value("-U", &flag, &mut args)?)?);
+            }
+            _ => return Err(format!("Unknown option '{}'", arg)),
+        }
+    }
+
+    parsed.input = match input.as_deref() {
+        None => return Err("Expected an input file, or `-` for stdin".to_owned()),
+        Some("-") => None,
+        Some(_) => input,
+    };
+
+    // build writes to stdout unless told otherwise, while preprocess defaults to out.basm
+    let to_stdout = match parsed.output.as_deref() {
+        Some(output) => output == "-",
+        None => subcommand == Subcommand::Build,
+    };
+    if parsed.depfile.is_some() && to_stdout {
+        return Err(
+            "A dependency file names the output file as its target, so -M needs an output file given with -o"
+                .to_owned(),
+        );
+    }
+
+    if parsed.trace_expansions && subcommand == Subcommand::Build && parsed.listing.is_none() {
+        return Err(
+            "`w4096 build` shows traced expansions in the listing, so --trace-expansions needs --listing"
+                .to_owned(),
+        );
+    }
+
+    Ok(Command::Run(parsed))
+}
+
+impl Subcommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subcommand::Build => "build",
+            Subcommand::Preprocess => "preprocess",
+            Subcommand::Asm => "asm",
+        }
+    }
+}
+
+impl Args {
+    /// Returns where output should be written, or None for stdout
+    pub fn output(&self) -> Option<&str> {
+        match self.output.as_deref() {
+            Some("-") => None,
+            output => output,
+        }
+    }
+}
+
+/// Errors if an option is used with a subcommand it doesn't apply to
+fn only_for(flag: &str, subcommand: Subcommand, allowed: &[Subcommand]) -> Result<(), String> {
+    if allowed.contains(&subcommand) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Option {} can't be used with `w4096 {}`",
+            flag,
+            subcommand.name()
+        ))
+    }
+}
@@ -0,0 +1,198 @@
+mod cli;
+
+use basm::CodeMap;
+use basm_cli::term;
+use cli::{Args, Format, Subcommand};
+use std::process::ExitCode;
+
+const EXIT_FAILURE: u8 = 1; // Something went wrong in one of the stages
+const EXIT_USAGE: u8 = 2; // The command line couldn't be understood
+
+const DEFAULT_PREPROCESS_OUTPUT: &str = "out.basm";
+
+fn main() -> ExitCode {
+    // Interpret command line arguments
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(args)) => args,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return ExitCode::SUCCESS;
+        }
+        Ok(cli::Command::Version) => {
+            println!("{}", cli::version());
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            term::set_color(term::ColorChoice::Auto);
+            error(&format!("{}\n\n{}", e, cli::usage()));
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    // Both stages print their diagnostics through basm-cli, so this sets them up for both
+    term::set_color(args.color);
+    term::set_verbosity(args.verbosity);
+
+    let result = match args.subcommand {
+        Subcommand::Build => build(&args),
+        Subcommand::Preprocess => preprocess_only(&args),
+        Subcommand::Asm => asm_only(&args),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error(&e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn error(msg: &str) {
+    term::error("W4096:", msg);
+}
+
+/// Preprocesses and assembles the input without writing anything in between
+fn build(args: &Args) -> Result<(), String> {
+    let (source, map, includes) = preprocess(args)?;
+    assemble(args, &source, Some(map))?;
+
+    // The command line makes sure there's an output file to name as the target
+    match args.output() {
+        Some(target) => write_depfile(args, target, &includes),
+        None => Ok(()),
+    }
+}
+
+/// Preprocesses the input, writing the output and its code map like basm-preprocessor does
+fn preprocess_only(args: &Args) -> Result<(), String> {
+    use basm_preprocessor::fileio::{self, Output};
+
+    let (source, map, includes) = preprocess(args)?;
+
+    let output = match args.output.as_deref() {
+        Some("-") => Output::Stdout,
+        Some(path) => Output::File(path.to_owned()),
+        None => Output::File(DEFAULT_PREPROCESS_OUTPUT.to_owned()),
+    };
+    let map_path = match (&args.map, &output) {
+        (Some(path), _) => Some(path.to_owned()),
+        (None, Output::File(path)) => Some(format!("{}.map", path)),
+        (None, Output::Stdout) => None,
+    };
+
+    let (source, map) = if args.line_markers.unwrap_or(map_path.is_none()) {
+        map.with_line_markers(&source)
+    } else {
+        (source, map)
+    };
+
+    fileio::write_asm_file(&output, &source)?;
+    if let Some(path) = map_path {
+        fileio::write_map_file(&path, &map)?;
+    }
+    if let Output::File(target) = &output {
+        write_depfile(args, target, &includes)?;
+    }
+
+    Ok(())
+}
+
+/// Assembles already preprocessed input, using its map file or #line markers if it has them
+fn asm_only(args: &Args) -> Result<(), String> {
+    let input = match args.input.as_deref() {
+        Some(filename) => basm::fileio::Input::File(filename.to_owned()),
+        None => basm::fileio::Input::Stdin,
+    };
+    let (source, map) = basm::fileio::get_input(&input, args.map.as_deref())?;
+
+    assemble(args, &source, map)
+}
+
+/// Preprocesses the input, also returning every file it included
+fn preprocess(args: &Args) -> Result<(String, CodeMap, Vec<String>), String> {
+    use basm_preprocessor::fileio::{self, Input};
+
+    let input = match args.input.as_deref() {
+        Some(filename) => Input::File(filename.to_owned()),
+        None => Input::Stdin,
+    };
+    let (filename, program) = fileio::get_input(&input)?;
+
+    let options = basm_preprocessor::Options {
+        filename,
+        include_dirs: args.include_dirs.clone(),
+        defines: args.defines.clone(),
+        undefines: args.undefines.clone(),
+        trace_expansions: args.trace_expansions,
+    };
+    basm_preprocessor::preprocess_with_dependencies(&program, &options)
+}
+
+/// Writes the dependency file asked for with -M, if there is one, saying target depends on the
+/// input and every file it included
+fn write_depfile(args: &Args, target: &str, includes: &[String]) -> Result<(), String> {
+    match &args.depfile {
+        Some(path) => {
+            basm_preprocessor::fileio::write_dep_file(path, target, args.input.as_deref(), includes)
+        }
+        None => Ok(()),
+    }
+}
+
+fn assemble(args: &Args, source: &str, map: Option<CodeMap>) -> Result<(), String> {
+    use basm::{fileio, listing};
+
+    let (tokens, map) = basm::tokenize(source, source_name(args), map, &basm_options(args))?;
+    if args.format == Format::Tokens {
+        let text: String = tokens.iter().map(|tok| format!("{}\n", tok)).collect();
+        return fileio::write_output(args.output(), text.as_bytes());
+    }
+
+    let ast = basm::parse(tokens, map.as_ref())?;
+    let image = basm::assemble_ast(&ast, map.as_ref())?;
+    if let Some(path) = &args.listing {
+        let mut text = listing::listing(&image, map.as_ref());
+        if args.trace_expansions {
+            text = with_traces(&text, &image, source);
+        }
+        fileio::write_output(Some(path), text.as_bytes())?;
+    }
+
+    let text: String = image
+        .statements
+        .iter()
+        .map(|expr| format!("{}\n", expr))
+        .collect();
+    fileio::write_output(args.output(), text.as_bytes())
+}
+
+/// Ends each line of a listing with the comment the preprocessor left on its statement's line,
+/// saying which macros were expanded there
+fn with_traces(listing: &str, image: &basm::Image, source: &str) -> String {
+    let source: Vec<&str> = source.lines().collect();
+
+    listing
+        .lines()
+        .zip(&image.statements)
+        .map(|(line, statement)| {
+            let trace = source
+                .get(statement.line.wrapping_sub(1))
+                .and_then(|s| s.rfind("; expanded ").map(|i| &s[i..]));
+            match trace {
+                Some(trace) => format!("{} {}\n", line, trace),
+                None => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+fn basm_options(args: &Args) -> basm::Options {
+    basm::Options {
+        suffix_conditions: args.suffix_conditions,
+    }
+}
+
+fn source_name(args: &Args) -> &str {
+    args.input.as_deref().unwrap_or("stdin")
+}